@@ -0,0 +1,944 @@
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/*
+ FF10-FF14  NR10-NR14  Channel 1, pulse with frequency sweep
+ FF16-FF19  NR21-NR24  Channel 2, pulse
+ FF1A-FF1E  NR30-NR34  Channel 3, wave output from the wave RAM at FF30-FF3F
+ FF20-FF23  NR41-NR44  Channel 4, noise from a linear feedback shift register
+
+ FF24 NR50  Bit6-4 left volume, Bit2-0 right volume
+ FF25 NR51  Bit7-4 channel 4-1 to the left, Bit3-0 channel 4-1 to the right
+ FF26 NR52  Bit7 APU power, Bit3-0 channel 4-1 on (read only)
+
+ The frame sequencer runs at 512 Hz on the falling edge of DIV bit 4:
+   step  0 2 4 6  length counters
+   step  2 6      channel 1 sweep
+   step  7        volume envelopes
+*/
+
+/// Samples per second and per side pushed into the sample buffer, one every
+/// 64 T-cycles.
+pub const APU_SAMPLE_RATE: u32 = 65536;
+
+const CYCLES_PER_SAMPLE: u32 = 4_194_304 / APU_SAMPLE_RATE;
+
+// when nobody pulls the samples only the last second is kept
+const MAX_BUFFERED_SAMPLES: usize = APU_SAMPLE_RATE as usize * 2;
+
+// DIV bit 4, bit 12 of the internal counter
+const FRAME_SEQUENCER_DIV_BIT: u16 = 0x1000;
+
+// the 12.5%, 25%, 50% and 75% waveforms
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// bits that always read back as 1, FF10 to FF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+#[derive(Clone, Copy, Default)]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    fn load(&mut self, max: u16, value: u8) {
+        self.counter = max - value as u16;
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    /// Returns true when the counter just ran out.
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.counter = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is off when the upper 5 bits of NRx2 are all 0.
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.timer);
+        writer.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.initial_volume = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+struct PulseChannel {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    // never enabled on channel 2, which has no NRx0 register
+    sweep: Sweep,
+}
+
+impl PulseChannel {
+    /// `register` is the offset from NRx0.
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.sweep.period = (value >> 4) & 0x07;
+                self.sweep.negate = value & 0x08 != 0;
+                self.sweep.shift = value & 0x07;
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(64, value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.timer = self.sweep_period();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    // a sweep period of 0 is treated as 8
+    fn sweep_period(&self) -> u8 {
+        if self.sweep.period == 0 {
+            8
+        } else {
+            self.sweep.period
+        }
+    }
+
+    /// Next frequency of the sweep, going over 2047 turns the channel off.
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.sweep.shadow_frequency >> self.sweep.shift;
+        let frequency = if self.sweep.negate {
+            self.sweep.shadow_frequency - delta
+        } else {
+            self.sweep.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.timer = self.sweep_period();
+
+        if self.sweep.enabled && self.sweep.period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep.shift != 0 {
+                self.frequency = frequency;
+                self.sweep.shadow_frequency = frequency;
+                // the overflow check runs a second time with the new value
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 4;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    /// Digital output (0-15), None when the DAC is off.
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step) & 1 != 0;
+        Some(if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_step);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.sweep.period);
+        writer.write_bool(self.sweep.negate);
+        writer.write_u8(self.sweep.shift);
+        writer.write_u8(self.sweep.timer);
+        writer.write_bool(self.sweep.enabled);
+        writer.write_u16(self.sweep.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 0x03;
+        self.duty_step = reader.read_u8()? & 0x07;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.sweep.period = reader.read_u8()?;
+        self.sweep.negate = reader.read_bool()?;
+        self.sweep.shift = reader.read_u8()?;
+        self.sweep.timer = reader.read_u8()?;
+        self.sweep.enabled = reader.read_bool()?;
+        self.sweep.shadow_frequency = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(256, value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        self.timer = (2048 - self.frequency) * 2;
+        self.position = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) & 0x1F;
+            // two 4-bit samples per byte, upper nibble first
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        // mute, 100%, 50%, 25%
+        Some(match self.volume_code {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            _ => self.sample >> 2,
+        })
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+        writer.write_u8(self.sample);
+        self.length.save_state(writer);
+        writer.write_bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.volume_code = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()? & 0x1F;
+        self.sample = reader.read_u8()?;
+        self.length.load_state(reader)?;
+        reader.read_into(&mut self.wave_ram)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct NoiseChannel {
+    enabled: bool,
+    clock_shift: u8,
+    short_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load(64, value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // 7-bit mode also feeds bit 6, repeating every 127 steps
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.short_mode);
+        writer.write_u8(self.divisor_code);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.clock_shift = reader.read_u8()? & 0x0F;
+        self.short_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()? & 0x07;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)
+    }
+}
+
+pub struct APU {
+    // last written values of FF10-FF2F, read back through READ_MASKS
+    registers: [u8; 0x20],
+    powered: bool,
+
+    channel1: PulseChannel,
+    channel2: PulseChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+
+    frame_sequencer_step: u8,
+    previous_div: u16,
+
+    sample_cycles: u32,
+    // high-pass filter state of the left and right outputs
+    capacitors: [f32; 2],
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 0x20],
+            powered: false,
+            channel1: PulseChannel::default(),
+            channel2: PulseChannel::default(),
+            channel3: WaveChannel::default(),
+            channel4: NoiseChannel::default(),
+            frame_sequencer_step: 0,
+            previous_div: 0,
+            sample_cycles: 0,
+            capacitors: [0.0; 2],
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn apu_read(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let channels = self.channel1.enabled as u8
+                    | (self.channel2.enabled as u8) << 1
+                    | (self.channel3.enabled as u8) << 2
+                    | (self.channel4.enabled as u8) << 3;
+                0x70 | (self.powered as u8) << 7 | channels
+            }
+            0xFF10..=0xFF2F => {
+                let register = (address - 0xFF10) as usize;
+                self.registers[register] | READ_MASKS[register]
+            }
+            0xFF30..=0xFF3F => self.channel3.wave_ram[(address - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn apu_write(&mut self, address: u16, value: u8) {
+        if let 0xFF30..=0xFF3F = address {
+            self.channel3.wave_ram[(address - 0xFF30) as usize] = value;
+            return;
+        }
+        if address == 0xFF26 {
+            self.set_power(value & 0x80 != 0);
+            return;
+        }
+        // the registers are read only while the APU is off
+        if !self.powered || !(0xFF10..=0xFF2F).contains(&address) {
+            return;
+        }
+
+        self.registers[(address - 0xFF10) as usize] = value;
+        match address {
+            0xFF10..=0xFF14 => self.channel1.write(address - 0xFF10, value),
+            0xFF16..=0xFF19 => self.channel2.write(address - 0xFF15, value),
+            0xFF1A..=0xFF1E => self.channel3.write(address - 0xFF1A, value),
+            0xFF20..=0xFF23 => self.channel4.write(address - 0xFF1F, value),
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            // turning the APU off clears every register but the wave RAM
+            let wave_ram = self.channel3.wave_ram;
+            self.registers = [0; 0x20];
+            self.channel1 = PulseChannel::default();
+            self.channel2 = PulseChannel::default();
+            self.channel3 = WaveChannel {
+                wave_ram,
+                ..WaveChannel::default()
+            };
+            self.channel4 = NoiseChannel::default();
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    /// Advances the APU by one T-cycle, `div` is the internal 16-bit counter
+    /// of the timer.
+    pub fn apu_tick(&mut self, div: u16) {
+        let div_falling_edge =
+            self.previous_div & FRAME_SEQUENCER_DIV_BIT != 0 && div & FRAME_SEQUENCER_DIV_BIT == 0;
+        self.previous_div = div;
+
+        if self.powered {
+            if div_falling_edge {
+                self.clock_frame_sequencer();
+            }
+            self.channel1.tick();
+            self.channel2.tick();
+            self.channel3.tick();
+            self.channel4.tick();
+        }
+
+        self.sample_cycles += 1;
+        if self.sample_cycles == CYCLES_PER_SAMPLE {
+            self.sample_cycles = 0;
+            self.push_sample();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step & 1 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) & 0x07;
+    }
+
+    /// Left and right output in -1.0..=1.0 before the high-pass filter.
+    fn mix(&self) -> [f32; 2] {
+        if !self.powered {
+            return [0.0; 2];
+        }
+
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
+
+        let mut mixed = [0.0; 2];
+        for (channel, output) in outputs.iter().enumerate() {
+            // a DAC maps 0-15 to a voltage, a DAC that is off outputs nothing
+            let Some(output) = output else {
+                continue;
+            };
+            let analog = *output as f32 / 7.5 - 1.0;
+            if nr51 & (0x10 << channel) != 0 {
+                mixed[0] += analog;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                mixed[1] += analog;
+            }
+        }
+
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        [
+            mixed[0] * left_volume / 32.0,
+            mixed[1] * right_volume / 32.0,
+        ]
+    }
+
+    fn push_sample(&mut self) {
+        // removes the DC offset like the capacitors on the output of the console
+        let charge_factor = 0.999958f32.powi(CYCLES_PER_SAMPLE as i32);
+        let mixed = self.mix();
+        for (side, input) in mixed.iter().enumerate() {
+            let output = input - self.capacitors[side];
+            self.capacitors[side] = input - output * charge_factor;
+            self.samples.push(output);
+        }
+
+        if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            let excess = self.samples.len() - MAX_BUFFERED_SAMPLES;
+            self.samples.drain(..excess);
+        }
+    }
+
+    /// Interleaved left/right samples at APU_SAMPLE_RATE produced since the
+    /// last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for APU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers);
+        writer.write_bool(self.powered);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_u16(self.previous_div);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.registers)?;
+        self.powered = reader.read_bool()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.frame_sequencer_step = reader.read_u8()? & 0x07;
+        self.previous_div = reader.read_u16()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{APU, APU_SAMPLE_RATE, CYCLES_PER_SAMPLE, FRAME_SEQUENCER_DIV_BIT};
+
+    fn powered_apu() -> APU {
+        let mut apu = APU::new();
+        apu.apu_write(0xFF26, 0x80);
+        apu.apu_write(0xFF24, 0x77);
+        apu.apu_write(0xFF25, 0xFF);
+        apu
+    }
+
+    // runs the APU with DIV counting like the timer does
+    fn run_cycles(apu: &mut APU, div: &mut u16, cycles: usize) {
+        for _ in 0..cycles {
+            *div = div.wrapping_add(1);
+            apu.apu_tick(*div);
+        }
+    }
+
+    #[test]
+    fn registers_read_back_with_unused_bits_set() {
+        let mut apu = APU::new();
+        assert_eq!(apu.apu_read(0xFF26), 0x70);
+        // ignored while the APU is off, wave RAM stays writable
+        apu.apu_write(0xFF12, 0xF0);
+        apu.apu_write(0xFF30, 0x12);
+        assert_eq!(apu.apu_read(0xFF12), 0x00);
+        assert_eq!(apu.apu_read(0xFF30), 0x12);
+
+        apu.apu_write(0xFF26, 0x80);
+        apu.apu_write(0xFF11, 0x80);
+        apu.apu_write(0xFF12, 0xF0);
+        apu.apu_write(0xFF14, 0x87);
+        assert_eq!(apu.apu_read(0xFF11), 0xBF);
+        assert_eq!(apu.apu_read(0xFF12), 0xF0);
+        assert_eq!(apu.apu_read(0xFF13), 0xFF);
+        assert_eq!(apu.apu_read(0xFF14), 0xBF);
+        assert_eq!(apu.apu_read(0xFF26), 0xF1);
+        assert_eq!(apu.apu_read(0xFF27), 0xFF);
+
+        apu.apu_write(0xFF26, 0x00);
+        assert_eq!(apu.apu_read(0xFF12), 0x00);
+        assert_eq!(apu.apu_read(0xFF26), 0x70);
+        assert_eq!(apu.apu_read(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn pulse_duty_and_period() {
+        let mut apu = powered_apu();
+        apu.apu_write(0xFF16, 0x80);
+        apu.apu_write(0xFF17, 0xF0);
+        // frequency 0x700: a duty step every (2048 - 0x700) * 4 = 1024 cycles
+        apu.apu_write(0xFF18, 0x00);
+        apu.apu_write(0xFF19, 0x87);
+
+        let mut div = 0;
+        let mut high_steps = 0;
+        for _ in 0..8 {
+            run_cycles(&mut apu, &mut div, 1024);
+            if apu.channel2.output() == Some(15) {
+                high_steps += 1;
+            }
+        }
+        // 50% duty
+        assert_eq!(high_steps, 4);
+    }
+
+    #[test]
+    fn length_counter_stops_the_channel() {
+        let mut apu = powered_apu();
+        let mut div = 0;
+        apu.apu_write(0xFF21, 0xF0);
+        // 64 - 62 = 2 length clocks
+        apu.apu_write(0xFF20, 62);
+        apu.apu_write(0xFF23, 0xC0);
+        assert_eq!(apu.apu_read(0xFF26) & 0x08, 0x08);
+
+        // one length clock every 2 frame sequencer steps
+        run_cycles(&mut apu, &mut div, FRAME_SEQUENCER_DIV_BIT as usize * 2 * 3);
+        assert_eq!(apu.apu_read(0xFF26) & 0x08, 0x00);
+    }
+
+    #[test]
+    fn envelope_and_dac() {
+        let mut apu = powered_apu();
+        let mut div = 0;
+        apu.apu_write(0xFF12, 0xA1);
+        apu.apu_write(0xFF14, 0x80);
+        assert_eq!(apu.channel1.envelope.volume, 10);
+
+        // the envelope is clocked on step 7 of the frame sequencer
+        run_cycles(&mut apu, &mut div, FRAME_SEQUENCER_DIV_BIT as usize * 2 * 8);
+        assert_eq!(apu.channel1.envelope.volume, 9);
+
+        // DAC off turns the channel off
+        apu.apu_write(0xFF12, 0x00);
+        assert_eq!(apu.apu_read(0xFF26) & 0x01, 0x00);
+        assert_eq!(apu.channel1.output(), None);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = powered_apu();
+        apu.apu_write(0xFF12, 0xF0);
+        // the overflow check on trigger: 0x7FF + (0x7FF >> 1) > 2047
+        apu.apu_write(0xFF10, 0x12);
+        apu.apu_write(0xFF13, 0xFF);
+        apu.apu_write(0xFF14, 0x87);
+        assert_eq!(apu.apu_read(0xFF26) & 0x01, 0x00);
+
+        // 0x400 sweeps up to 0x500
+        apu.apu_write(0xFF13, 0x00);
+        apu.apu_write(0xFF14, 0x84);
+        let mut div = 0;
+        run_cycles(&mut apu, &mut div, FRAME_SEQUENCER_DIV_BIT as usize * 2 * 3);
+        assert_eq!(apu.channel1.frequency, 0x500);
+        assert_eq!(apu.apu_read(0xFF26) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn wave_channel_plays_wave_ram() {
+        let mut apu = powered_apu();
+        apu.apu_write(0xFF30, 0x1F);
+        apu.apu_write(0xFF1A, 0x80);
+        apu.apu_write(0xFF1C, 0x20);
+        // a sample every (2048 - 0x7FF) * 2 = 2 cycles
+        apu.apu_write(0xFF1D, 0xFF);
+        apu.apu_write(0xFF1E, 0x87);
+
+        let mut div = 0;
+        run_cycles(&mut apu, &mut div, 2 * 32);
+        assert_eq!(apu.channel3.output(), Some(0x1));
+        run_cycles(&mut apu, &mut div, 2);
+        assert_eq!(apu.channel3.output(), Some(0xF));
+
+        // 50% volume
+        apu.apu_write(0xFF1C, 0x40);
+        assert_eq!(apu.channel3.output(), Some(0x7));
+    }
+
+    #[test]
+    fn noise_lfsr_sequence() {
+        let mut apu = powered_apu();
+        apu.apu_write(0xFF21, 0xF0);
+        apu.apu_write(0xFF22, 0x08);
+        apu.apu_write(0xFF23, 0x80);
+        assert_eq!(apu.channel4.lfsr, 0x7FFF);
+
+        // the 7-bit mode repeats after 127 steps
+        let mut div = 0;
+        run_cycles(&mut apu, &mut div, 8);
+        let start = apu.channel4.lfsr & 0x7F;
+        run_cycles(&mut apu, &mut div, 8 * 127);
+        assert_eq!(apu.channel4.lfsr & 0x7F, start);
+    }
+
+    #[test]
+    fn produces_stereo_samples() {
+        let mut apu = powered_apu();
+        apu.apu_write(0xFF25, 0x10);
+        apu.apu_write(0xFF12, 0xF0);
+        apu.apu_write(0xFF14, 0x80);
+
+        let mut div = 0;
+        run_cycles(&mut apu, &mut div, CYCLES_PER_SAMPLE as usize * 100);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 200);
+        // channel 1 only goes to the left side
+        assert!(samples.iter().step_by(2).any(|sample| *sample != 0.0));
+        assert!(samples
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|sample| *sample == 0.0));
+        assert!(apu.take_samples().is_empty());
+
+        run_cycles(
+            &mut apu,
+            &mut div,
+            CYCLES_PER_SAMPLE as usize * APU_SAMPLE_RATE as usize * 2,
+        );
+        assert_eq!(apu.take_samples().len(), APU_SAMPLE_RATE as usize * 2);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Output rates frontends can ask the resampler for.
+pub const OUTPUT_SAMPLE_RATES: [u32; 2] = [44100, 48000];
+
+pub const LIVE_SAMPLE_RATE: u32 = 48000;
+
+/// Destination of the resampled audio, interleaved left/right at
+/// `sample_rate()`.
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+    fn push_samples(&mut self, samples: &[f32]);
+}
+
+/// Converts interleaved stereo samples between two rates with linear
+/// interpolation, keeping the fractional position between calls so the APU
+/// output can be fed in chunks of any size.
+pub struct Resampler {
+    step: f64,
+    position: f64,
+    previous: [f32; 2],
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            step: input_rate as f64 / output_rate as f64,
+            position: 0.0,
+            previous: [0.0; 2],
+        }
+    }
+
+    pub fn resample(&mut self, input: &[f32]) -> Vec<f32> {
+        let frames = input.len() / 2;
+        let mut output = Vec::with_capacity((frames as f64 / self.step) as usize * 2 + 2);
+
+        // position 0.0 is the last frame of the previous chunk, 1.0 the first
+        // frame of this one
+        while self.position < frames as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            for side in 0..2 {
+                let from = if index == 0 {
+                    self.previous[side]
+                } else {
+                    input[(index - 1) * 2 + side]
+                };
+                let to = input[index * 2 + side];
+                output.push(from + (to - from) * fraction);
+            }
+            self.position += self.step;
+        }
+
+        if frames > 0 {
+            self.position -= frames as f64;
+            self.previous = [input[(frames - 1) * 2], input[(frames - 1) * 2 + 1]];
+        }
+        output
+    }
+}
+
+/*
+ WAV layout written by WavWriter, 16-bit signed little-endian PCM:
+   "RIFF" u32 file size - 8, "WAVE"
+   "fmt " u32 16, u16 format (1 = PCM), u16 channels, u32 sample rate,
+          u32 byte rate, u16 block align, u16 bits per sample
+   "data" u32 data size, samples
+*/
+const WAV_HEADER_SIZE: u32 = 44;
+const WAV_CHANNELS: u16 = 2;
+const WAV_BITS_PER_SAMPLE: u16 = 16;
+
+/// Records stereo audio to a WAV file, the sizes in the header are filled in
+/// by `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            data_size: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = WAV_CHANNELS * WAV_BITS_PER_SAMPLE / 8;
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&WAV_CHANNELS.to_le_bytes())?;
+        file.write_all(&self.sample_rate.to_le_bytes())?;
+        file.write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&WAV_BITS_PER_SAMPLE.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&self.data_size.to_le_bytes())
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl AudioSink for WavWriter {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        if let Err(e) = self.write_samples(samples) {
+            println!("cannot write audio samples: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+pub use live::LiveSink;
+
+/// Sound card output, None when it can't be opened or the emulator was built
+/// without the `audio` feature.
+#[cfg(feature = "audio")]
+pub fn open_live_sink(sample_rate: u32) -> Option<Box<dyn AudioSink>> {
+    match LiveSink::new(sample_rate) {
+        Ok(sink) => Some(Box::new(sink)),
+        Err(e) => {
+            println!("audio disabled: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+pub fn open_live_sink(_sample_rate: u32) -> Option<Box<dyn AudioSink>> {
+    None
+}
+
+#[cfg(feature = "audio")]
+mod live {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use cpal::{
+        traits::{DeviceTrait, HostTrait, StreamTrait},
+        BufferSize, SampleRate, Stream, StreamConfig,
+    };
+
+    use super::AudioSink;
+
+    // about 100 ms at 48 kHz, older samples are dropped when the emulator
+    // runs ahead of the sound card
+    const MAX_QUEUED_SAMPLES: usize = 48000 / 10 * 2;
+
+    /// Plays the samples on the default output device.
+    pub struct LiveSink {
+        _stream: Stream,
+        queue: Arc<Mutex<VecDeque<f32>>>,
+        sample_rate: u32,
+    }
+
+    impl LiveSink {
+        pub fn new(sample_rate: u32) -> Result<Self, String> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or("no audio output device")?;
+            let config = StreamConfig {
+                channels: 2,
+                sample_rate: SampleRate(sample_rate),
+                buffer_size: BufferSize::Default,
+            };
+
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let stream_queue = Arc::clone(&queue);
+            let stream = device
+                .build_output_stream(
+                    &config,
+                    move |data: &mut [f32], _| {
+                        let mut queue = stream_queue.lock().unwrap();
+                        for sample in data.iter_mut() {
+                            // silence when the emulator falls behind
+                            *sample = queue.pop_front().unwrap_or(0.0);
+                        }
+                    },
+                    |e| println!("audio stream error: {}", e),
+                    None,
+                )
+                .map_err(|e| e.to_string())?;
+            stream.play().map_err(|e| e.to_string())?;
+
+            Ok(Self {
+                _stream: stream,
+                queue,
+                sample_rate,
+            })
+        }
+    }
+
+    impl AudioSink for LiveSink {
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn push_samples(&mut self, samples: &[f32]) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(samples);
+            if queue.len() > MAX_QUEUED_SAMPLES {
+                let excess = queue.len() - MAX_QUEUED_SAMPLES;
+                queue.drain(..excess);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Resampler, WavWriter};
+
+    #[test]
+    fn resampler_output_rate() {
+        let mut resampler = Resampler::new(65536, 48000);
+        let input = vec![0.5; 65536 * 2];
+        let mut output_frames = 0;
+        // the same second of audio in uneven chunks
+        for chunk in input.chunks(1234 * 2) {
+            output_frames += resampler.resample(chunk).len() / 2;
+        }
+        assert!((47999..=48001).contains(&output_frames));
+    }
+
+    #[test]
+    fn resampler_interpolates() {
+        let mut resampler = Resampler::new(2, 4);
+        let output = resampler.resample(&[1.0, -1.0, 3.0, -3.0]);
+        // starts halfway from the previous (silent) frame
+        assert_eq!(output, vec![0.0, 0.0, 0.5, -0.5, 1.0, -1.0, 2.0, -2.0]);
+        let output = resampler.resample(&[5.0, -5.0]);
+        assert_eq!(output, vec![3.0, -3.0, 4.0, -4.0]);
+    }
+
+    #[test]
+    fn wav_header_and_data() {
+        let path = std::env::temp_dir().join("rusty_gb_wav_header_and_data.wav");
+        let mut writer = WavWriter::create(&path, 44100).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        writer.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes(data[22..24].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        assert_eq!(
+            u32::from_le_bytes(data[28..32].try_into().unwrap()),
+            44100 * 4
+        );
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        // out of range samples are clipped
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+    }
+}
@@ -14,9 +14,16 @@ FF80	FFFE	High RAM (HRAM)
 FFFF	FFFF	Interrupt Enable register (IE)
 */
 
-use std::{thread, time::Duration};
-
-use crate::{cartridge::Cartridge, dma::DMA, io::IO, ppu::PPU, ram::RamContext};
+use crate::{
+    apu::APU,
+    cartridge::{mbc5::RumbleCallback, Cartridge},
+    dma::{DMA, HDMA},
+    io::IO,
+    model::Model,
+    ppu::{PpuMode, PPU},
+    ram::RamContext,
+    savestate::{SaveState, SaveStateError, StateReader, StateWriter},
+};
 
 // use crate::ram::RamContext;
 
@@ -28,8 +35,25 @@ pub struct Bus<'a> {
 
     pub ppu: PPU,
 
+    pub apu: APU,
+
     pub dma: DMA,
 
+    pub hdma: HDMA,
+
+    // mapped over the start of the cartridge ROM until FF50 is written
+    boot_rom: Option<Vec<u8>>,
+
+    model: Model,
+
+    // CGB mode, and the KEY1 speed switch
+    cgb: bool,
+    double_speed: bool,
+    speed_switch_armed: bool,
+
+    // value every LY read returns, trace logs are compared with it stubbed
+    ly_stub: Option<u8>,
+
     dbg_message: [u8; 1024],
     dbg_message_size: usize,
 }
@@ -41,24 +65,96 @@ impl<'a> Bus<'a> {
         // load the cartridge
         let cartridge = Cartridge::load(rom_file);
 
+        Bus::with_cartridge(cartridge)
+    }
+
+    /// Runs the cartridge on the hardware it was made for, see
+    /// `Model::for_header`.
+    pub fn with_cartridge(cartridge: Cartridge<'a>) -> Self {
+        let model = Model::for_header(cartridge.header());
+        Bus::with_model(cartridge, model)
+    }
+
+    pub fn with_model(cartridge: Cartridge<'a>, model: Model) -> Self {
         // initialize the RAM
         let ram: RamContext = RamContext::new();
 
         let io = IO::new();
 
-        let ppu = PPU::new();
+        // CGB flagged ROMs run in color on CGB hardware, the others as on a DMG
+        let cgb = model.is_cgb() && cartridge.header().supports_cgb();
+
+        let mut ppu = PPU::new();
+        ppu.set_cgb_mode(cgb);
+        ppu.set_stat_write_bug(model.has_stat_write_bug());
+
+        let apu = APU::new();
 
         let dma = DMA::new();
 
-        Self {
+        let mut bus = Self {
             cartridge,
             ram,
             io,
             interrupt_enable_register: 0,
             ppu,
+            apu,
             dma,
+            hdma: HDMA::new(),
+            boot_rom: None,
+            model,
+            cgb,
+            double_speed: false,
+            ly_stub: None,
+            speed_switch_armed: false,
             dbg_message: [0; 1024],
             dbg_message_size: 0,
+        };
+        bus.set_post_boot_state();
+        bus
+    }
+
+    fn set_post_boot_state(&mut self) {
+        for (address, value) in self.model.post_boot_io(self.cgb) {
+            self.bus_write8(address, value);
+        }
+        self.io.timer.set_div(self.model.post_boot_div());
+    }
+
+    /// Maps the boot ROM at 0x0000 and puts the IO registers back in their
+    /// power on state, the boot ROM sets them up itself.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        // CGB boot ROMs have 0x900 bytes with a hole at 0x100-0x1FF where the
+        // cartridge header shows through
+        if boot_rom.len() != self.model.boot_rom_size() {
+            return Err(format!(
+                "boot ROM has {} bytes, the {:?} one has {}",
+                boot_rom.len(),
+                self.model,
+                self.model.boot_rom_size()
+            ));
+        }
+        self.boot_rom = Some(boot_rom);
+
+        self.io = IO::new();
+        self.io.timer.set_div(0);
+        self.apu = APU::new();
+        self.ppu.lcd_write(0xFF40, 0x00);
+        self.ppu.lcd_write(0xFF47, 0x00);
+        Ok(())
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    fn boot_rom_read(&self, address: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        let address = address as usize;
+        if address < 0x100 || ((0x200..boot_rom.len()).contains(&address)) {
+            Some(boot_rom[address])
+        } else {
+            None
         }
     }
 
@@ -90,6 +186,71 @@ impl<'a> Bus<'a> {
         }
     }
 
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.cartridge.set_rumble_callback(callback);
+    }
+
+    pub fn cart_tick(&mut self, cycles: usize) {
+        self.cartridge.cart_tick(cycles);
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn cartridge(&self) -> &Cartridge<'a> {
+        &self.cartridge
+    }
+
+    /// Makes LY reads return `value`, the PPU keeps running as usual.
+    pub fn stub_ly(&mut self, value: u8) {
+        self.ly_stub = Some(value);
+    }
+
+    /// Bank currently mapped at `address`, None outside the switchable areas.
+    pub fn bank_at(&self, address: u16) -> Option<usize> {
+        match address {
+            0x4000..=0x7FFF => Some(self.cartridge.rom_bank()),
+            0x8000..=0x9FFF => Some(self.ppu.vram_bank()),
+            0xA000..=0xBFFF => Some(self.cartridge.ram_bank()),
+            0xD000..=0xDFFF => Some(self.ram.wram_bank()),
+            _ => None,
+        }
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /*
+     FF4D KEY1, CGB only
+     Bit7   Current speed  (0=Normal, 1=Double)  (read only)
+     Bit0   Prepare switch (0=No, 1=Switch on the next STOP)
+    */
+    fn key1_read(&self) -> u8 {
+        0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+    }
+
+    /// Called by STOP, toggles the CPU speed if KEY1 armed the switch.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        // the switch resets DIV
+        self.io.timer.timer_write(0xFF04, 0);
+        true
+    }
+
+    fn is_cgb_ppu_register(&self, address: u16) -> bool {
+        self.cgb && matches!(address, 0xFF4F | 0xFF68..=0xFF6B)
+    }
+
     pub fn get_ie_register(&self) -> u8 {
         self.interrupt_enable_register
     }
@@ -100,8 +261,9 @@ impl<'a> Bus<'a> {
 
     pub fn bus_read(&self, address: u16) -> u8 {
         if address < 0x8000 {
-            // RM data
-            self.cartridge.cart_read(address)
+            // RM data, the boot ROM hides its part of it until FF50 is written
+            self.boot_rom_read(address)
+                .unwrap_or_else(|| self.cartridge.cart_read(address))
         } else if address < 0xA000 {
             // character map data
             self.ppu.ppu_vram_read(address)
@@ -125,6 +287,24 @@ impl<'a> Bus<'a> {
         } else if address < 0xFF00 {
             // Not Usable	Nintendo says use of this area is prohibited
             0
+        } else if (0xFF10..=0xFF3F).contains(&address) {
+            // audio registers and wave RAM
+            self.apu.apu_read(address)
+        } else if let (0xFF44, Some(ly)) = (address, self.ly_stub) {
+            ly
+        } else if ((0xFF40..=0xFF4B).contains(&address) && address != 0xFF46)
+            || self.is_cgb_ppu_register(address)
+        {
+            // LCD registers
+            self.ppu.lcd_read(address)
+        } else if address == 0xFF50 {
+            0xFF
+        } else if self.cgb && address == 0xFF4D {
+            self.key1_read()
+        } else if self.cgb && (0xFF51..=0xFF55).contains(&address) {
+            self.hdma.hdma_read(address)
+        } else if self.cgb && address == 0xFF70 {
+            self.ram.svbk_read()
         } else if address < 0xFF80 {
             // IO registers
             self.io.io_read(address)
@@ -168,7 +348,27 @@ impl<'a> Bus<'a> {
             // Not Usable	Nintendo says use of this area is prohibited
         } else if address == 0xFF46 {
             self.dma.dma_start(value);
-            println!("DMA START");
+        } else if (0xFF10..=0xFF3F).contains(&address) {
+            // audio registers and wave RAM
+            self.apu.apu_write(address, value);
+        } else if (0xFF40..=0xFF4B).contains(&address) || self.is_cgb_ppu_register(address) {
+            // LCD registers
+            let interrupts = self.ppu.lcd_write(address, value);
+            if interrupts != 0 {
+                let interrupt_flags = self.io.get_if_flag();
+                self.io.set_if_flag(interrupt_flags | interrupts);
+            }
+        } else if address == 0xFF50 {
+            // unmapping the boot ROM can't be undone
+            if value != 0 {
+                self.boot_rom = None;
+            }
+        } else if self.cgb && address == 0xFF4D {
+            self.speed_switch_armed = value & 0x01 != 0;
+        } else if self.cgb && (0xFF51..=0xFF55).contains(&address) {
+            self.hdma.hdma_write(address, value);
+        } else if self.cgb && address == 0xFF70 {
+            self.ram.svbk_write(value);
         } else if address < 0xFF80 {
             // IO registers
             self.io.io_write(address, value)
@@ -206,14 +406,27 @@ impl<'a> Bus<'a> {
 
         self.dma.active = self.dma.byte < 0xA0;
 
-        if !self.dma.dma_is_transferring() {
-            println!("DMA DONE!");
-            thread::sleep(Duration::from_secs(2));
-            true
-        } else {
-            false
+        !self.dma.dma_is_transferring()
+    }
+    /// Runs once per dot, a H-blank transfer gets a block due each time the
+    /// PPU enters HBlank.
+    pub fn hdma_tick(&mut self) {
+        let in_hblank = self.ppu.lcd_enabled() && self.ppu.mode() == PpuMode::HBlank;
+        if in_hblank && !self.hdma.in_hblank && self.hdma.hblank {
+            self.hdma.block_due = true;
         }
+        self.hdma.in_hblank = in_hblank;
     }
+
+    /// Copies the next 0x10 bytes of the VRAM DMA, the CPU charges the time.
+    pub fn hdma_copy_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for offset in 0..0x10 {
+            let value = self.bus_read(source.wrapping_add(offset));
+            self.ppu.ppu_vram_write(destination + offset, value);
+        }
+    }
+
     pub fn fetch_tile(&self, tile_number: usize) -> [u8; 16] {
         if tile_number > 384 {
             panic!("Maximum tiles supported: {}", 384);
@@ -227,3 +440,47 @@ impl<'a> Bus<'a> {
         tile_array
     }
 }
+
+impl SaveState for Bus<'_> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cartridge.save_state(writer);
+        self.ram.save_state(writer);
+        self.io.save_state(writer);
+        self.ppu.save_state(writer);
+        self.dma.save_state(writer);
+        writer.write_u8(self.interrupt_enable_register);
+        self.apu.save_state(writer);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        self.hdma.save_state(writer);
+        writer.write_bool(self.boot_rom_mapped());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cartridge.load_state(reader)?;
+        self.ram.load_state(reader)?;
+        self.io.load_state(reader)?;
+        self.ppu.load_state(reader)?;
+        self.dma.load_state(reader)?;
+        self.interrupt_enable_register = reader.read_u8()?;
+        if reader.version >= 5 {
+            self.apu.load_state(reader)?;
+        }
+        if reader.version >= 6 {
+            self.double_speed = reader.read_bool()?;
+            self.speed_switch_armed = reader.read_bool()?;
+            self.hdma.load_state(reader)?;
+        }
+        if reader.version >= 8 {
+            let boot_rom_mapped = reader.read_bool()?;
+            if !boot_rom_mapped {
+                self.boot_rom = None;
+            } else if !self.boot_rom_mapped() {
+                return Err(SaveStateError::BootRomMissing);
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,23 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom};
 use std::num::Wrapping;
+use std::path::{Path, PathBuf};
 
 use crate::cartridge::licensee_code::{NewLicenseeCode, OldLicenseeCode};
+use crate::cartridge::mapper::{Mapper, MapperRegistry};
+use crate::cartridge::mbc5::RumbleCallback;
+use crate::cartridge::rom_types::RomTypes;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// battery backed RAM written since the last flush is saved once per emulated second
+const SAVE_FLUSH_CYCLES: usize = 4_194_304;
 
 #[derive(Debug)]
 #[allow(dead_code)]
-struct RomHeader {
+pub struct RomHeader {
     entry_point: [u8; 0x4],
     nintendo_logo: [u8; 0x30],
     title: [u8; 0x10],
@@ -29,12 +40,20 @@ struct RomHeader {
 pub struct Cartridge<'rom> {
     filename: &'rom str,
     rom_size: usize,
-    rom_data: Vec<u8>,
     rom_header: RomHeader,
+    mapper: Box<dyn Mapper>,
+    save_dirty: bool,
+    cycles_since_flush: usize,
 }
 
 impl<'rom> Cartridge<'rom> {
     pub fn load(filename: &'rom str) -> Cartridge<'rom> {
+        Cartridge::load_with_registry(filename, &MapperRegistry::new())
+    }
+
+    /// Loads the ROM file, letting `registry` provide mappers the emulator
+    /// doesn't know about.
+    pub fn load_with_registry(filename: &'rom str, registry: &MapperRegistry) -> Cartridge<'rom> {
         let mut rom_file = File::open(filename).unwrap();
         rom_file.seek(SeekFrom::End(0)).unwrap();
         let rom_size = rom_file.stream_position().unwrap() as usize;
@@ -56,13 +75,13 @@ impl<'rom> Cartridge<'rom> {
             "\t ROM Size: {} KB",
             32 * (1 << rom_header.rom_size) as usize
         );
-        println!("\t RAM Size: {}", rom_header.ram_size);
-        // let gbc_rom =  match rom_header.cgb_flag {
-        //     0x80 => "Color Backward compatible",
-        //     0xC0 => "Color Only",
-        //     _ => "Unspecified"
-        // };
-        // println!("\t Color mode: {}", gbc_rom);
+        println!("\t RAM Size: {} KB", rom_header.ram_size_bytes() / 1024);
+        let gbc_rom = match rom_header.cgb_flag {
+            0x80 => "Color Backward compatible",
+            0xC0 => "Color Only",
+            _ => "Unspecified",
+        };
+        println!("\t Color mode: {}", gbc_rom);
         println!(
             "\t Licensee Code: {}, {}, {}",
             rom_header.old_licensee_code,
@@ -82,13 +101,44 @@ impl<'rom> Cartridge<'rom> {
             calculated_checksum, rom_header.header_checksum, checksum_status
         );
 
+        let mut mapper = registry.create(rom_data, &rom_header);
+
+        if rom_header.has_battery() {
+            let save_path = Cartridge::save_path_for(filename);
+            if let Ok(save_data) = fs::read(&save_path) {
+                println!("\t Save file: {}", save_path.display());
+                mapper.load_save_data(&save_data);
+            }
+        }
+
         Cartridge {
             filename,
             rom_size,
-            rom_data,
             rom_header,
+            mapper,
+            save_dirty: false,
+            cycles_since_flush: 0,
         }
     }
+
+    /// `game.gb` saves to `game.sav`, next to the ROM like other emulators do.
+    pub fn save_path_for(filename: &str) -> PathBuf {
+        Path::new(filename).with_extension("sav")
+    }
+
+    /// Writes battery backed RAM (and the MBC3 clock) to the `.sav` file,
+    /// does nothing for cartridges without a battery.
+    pub fn save(&mut self) {
+        if !self.rom_header.has_battery() {
+            return;
+        }
+        let save_path = Cartridge::save_path_for(self.filename);
+        match fs::write(&save_path, self.mapper.save_data()) {
+            Ok(_) => self.save_dirty = false,
+            Err(e) => println!("cannot write save file {}: {}", save_path.display(), e),
+        }
+    }
+
     fn calculate_checksum(rom_data: &[u8]) -> u8 {
         let mut checksum = Wrapping(0u8);
         for byte in rom_data[0x0134..0x014D].iter() {
@@ -98,16 +148,124 @@ impl<'rom> Cartridge<'rom> {
         checksum.0
     }
 
+    /// Registers the callback notified when a rumble cartridge toggles its
+    /// motor, ignored for cartridges without one.
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.mapper.set_rumble_callback(callback);
+    }
+
     pub fn cart_read(&self, address: u16) -> u8 {
-        // For now we only support ROM ONLY cart type ...
-        return self.rom_data[address as usize];
+        if address < 0x8000 {
+            self.mapper.read_rom(address)
+        } else {
+            self.mapper.read_ram(address)
+        }
     }
 
     pub fn cart_write(&mut self, address: u16, value: u8) {
-        // For now we only support ROM ONLY cart type ...
-        println!("cart_write({:#02X}, {:#02X})", address, value);
-        // self.rom_data[address as usize] = value;
-        // unimplemented!();
+        if address < 0x8000 {
+            self.mapper.write_rom(address, value);
+        } else {
+            self.mapper.write_ram(address, value);
+            self.save_dirty = true;
+        }
+    }
+
+    pub fn cart_tick(&mut self, cycles: usize) {
+        self.mapper.tick(cycles);
+
+        self.cycles_since_flush += cycles;
+        if self.cycles_since_flush >= SAVE_FLUSH_CYCLES {
+            self.cycles_since_flush = 0;
+            if self.save_dirty {
+                self.save();
+            }
+        }
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.mapper.rom_bank()
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        self.mapper.ram_bank()
+    }
+
+    pub fn header(&self) -> &RomHeader {
+        &self.rom_header
+    }
+
+    /// Header and global checksum, the first block of a snapshot.
+    pub fn save_rom_identity(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.rom_header.header_checksum);
+        writer.write_u16(self.rom_header.global_checksum);
+    }
+
+    pub fn check_rom_identity(&self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let header_checksum = reader.read_u8()?;
+        let global_checksum = reader.read_u16()?;
+        if header_checksum != self.rom_header.header_checksum
+            || global_checksum != self.rom_header.global_checksum
+        {
+            return Err(SaveStateError::RomMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Reads `address` (0000-7FFF) from the given 16 KiB ROM bank, the bank number
+/// wraps around the actual ROM size like the unconnected address lines do.
+pub fn read_rom_bank(rom_data: &[u8], bank: usize, address: u16) -> u8 {
+    let bank_count = (rom_data.len() / ROM_BANK_SIZE).max(1);
+    let offset = (bank % bank_count) * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1));
+    rom_data.get(offset).copied().unwrap_or(0xFF)
+}
+
+/// Restores battery backed RAM from a save file, a shorter file only fills
+/// the beginning of the RAM.
+pub fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let length = ram.len().min(data.len());
+    ram[..length].copy_from_slice(&data[..length]);
+}
+
+fn ram_bank_offset(ram: &[u8], bank: usize, address: u16) -> usize {
+    let bank_offset = (bank * RAM_BANK_SIZE) % ram.len();
+    (bank_offset + (address as usize & (RAM_BANK_SIZE - 1))) % ram.len()
+}
+
+/// Reads `address` (A000-BFFF) from the given 8 KiB external RAM bank.
+pub fn read_ram_bank(ram: &[u8], bank: usize, address: u16) -> u8 {
+    if ram.is_empty() {
+        return 0xFF;
+    }
+    ram[ram_bank_offset(ram, bank, address)]
+}
+
+pub fn write_ram_bank(ram: &mut [u8], bank: usize, address: u16, value: u8) {
+    if ram.is_empty() {
+        return;
+    }
+    let offset = ram_bank_offset(ram, bank, address);
+    ram[offset] = value;
+}
+
+impl SaveState for Cartridge<'_> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.mapper.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        if reader.version < 9 {
+            // the ROM identity moved to the start of the snapshot in version 9
+            self.check_rom_identity(reader)?;
+        }
+        self.mapper.load_state(reader)
+    }
+}
+
+impl Drop for Cartridge<'_> {
+    fn drop(&mut self) {
+        self.save();
     }
 }
 
@@ -147,6 +305,37 @@ impl RomHeader {
         }
     }
 
+    pub fn cartridge_type(&self) -> u8 {
+        self.cartridge_type
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    /// Bit 7 of the CGB flag marks ROMs that use the CGB features, bit 6 alone
+    /// has no effect.
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn has_battery(&self) -> bool {
+        RomTypes::try_from(self.cartridge_type)
+            .map(|rom_type| rom_type.has_battery())
+            .unwrap_or(false)
+    }
+
+    pub fn ram_size_bytes(&self) -> usize {
+        match self.ram_size {
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => 0,
+        }
+    }
+
     pub fn title_name(&self) -> String {
         // String::from_utf8_lossy(&self.title).to_string()
         "".to_string()
@@ -156,3 +345,17 @@ impl RomHeader {
     //     println!("Cartridge type: {}", self.cartridge_type);
     // }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::ROM_BANK_SIZE;
+
+    // every byte of a bank holds the bank number
+    pub fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = Vec::with_capacity(banks * ROM_BANK_SIZE);
+        for bank in 0..banks {
+            rom.extend(std::iter::repeat_n(bank as u8, ROM_BANK_SIZE));
+        }
+        rom
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use crate::cartridge::cart::RomHeader;
+use crate::cartridge::mbc1::Mbc1;
+use crate::cartridge::mbc2::Mbc2;
+use crate::cartridge::mbc3::Mbc3;
+use crate::cartridge::mbc5::{Mbc5, RumbleCallback};
+use crate::cartridge::rom_only::RomOnly;
+use crate::cartridge::rom_types::RomTypes;
+use crate::cartridge::rtc::{Rtc, SystemClock};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Cartridge hardware sitting behind 0000-7FFF (ROM and its control registers)
+/// and A000-BFFF (external RAM or mapper registers).
+pub trait Mapper {
+    fn read_rom(&self, address: u16) -> u8;
+    fn write_rom(&mut self, address: u16, value: u8);
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    /// State that survives power off (battery backed RAM, clock registers).
+    fn save_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_save_data(&mut self, _data: &[u8]) {}
+
+    /// Mapper registers and RAM for machine save states, the default only
+    /// keeps the battery save data.
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.save_data());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let data = reader.read_bytes()?;
+        self.load_save_data(data);
+        Ok(())
+    }
+
+    /// ROM bank mapped at 4000-7FFF.
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// External RAM bank mapped at A000-BFFF.
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    /// Called with the T-cycles elapsed since the previous call.
+    fn tick(&mut self, _cycles: usize) {}
+
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {}
+}
+
+pub type MapperFactory = Box<dyn Fn(Vec<u8>, &RomHeader) -> Box<dyn Mapper>>;
+
+/// Picks the mapper for a cartridge type. Registered factories take
+/// precedence over the built-in ones, so homebrew or bootleg boards can
+/// claim a cartridge type byte of their own or replace a built-in mapper.
+#[derive(Default)]
+pub struct MapperRegistry {
+    factories: HashMap<u8, MapperFactory>,
+}
+
+impl MapperRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    pub fn register<F>(&mut self, cartridge_type: u8, factory: F)
+    where
+        F: Fn(Vec<u8>, &RomHeader) -> Box<dyn Mapper> + 'static,
+    {
+        self.factories.insert(cartridge_type, Box::new(factory));
+    }
+
+    pub fn create(&self, rom_data: Vec<u8>, rom_header: &RomHeader) -> Box<dyn Mapper> {
+        match self.factories.get(&rom_header.cartridge_type()) {
+            Some(factory) => factory(rom_data, rom_header),
+            None => builtin_mapper(rom_data, rom_header),
+        }
+    }
+}
+
+fn builtin_mapper(rom_data: Vec<u8>, rom_header: &RomHeader) -> Box<dyn Mapper> {
+    let ram_size = rom_header.ram_size_bytes();
+    match RomTypes::try_from(rom_header.cartridge_type()) {
+        Ok(RomTypes::RomOnly | RomTypes::RomRam1 | RomTypes::RomRamBattery1) => {
+            Box::new(RomOnly::new(rom_data, ram_size))
+        }
+        Ok(RomTypes::MBC1 | RomTypes::Mbc1Ram | RomTypes::Mbc1RamBattery) => {
+            Box::new(Mbc1::new(rom_data, ram_size))
+        }
+        Ok(RomTypes::MBC2 | RomTypes::Mbc2Battery) => Box::new(Mbc2::new(rom_data)),
+        Ok(RomTypes::Mbc3TimerBattery | RomTypes::Mbc3TimerRamBattery2) => {
+            let rtc = Rtc::new(Box::new(SystemClock));
+            Box::new(Mbc3::new(rom_data, ram_size, Some(rtc)))
+        }
+        Ok(RomTypes::MBC3 | RomTypes::Mbc3Ram2 | RomTypes::Mbc3RamBattery2) => {
+            Box::new(Mbc3::new(rom_data, ram_size, None))
+        }
+        Ok(RomTypes::MBC5 | RomTypes::Mbc5Ram | RomTypes::Mbc5RamBattery) => {
+            Box::new(Mbc5::new(rom_data, ram_size, false))
+        }
+        Ok(RomTypes::Mbc5Rumble | RomTypes::Mbc5RumbleRam | RomTypes::Mbc5RumbleRamBattery) => {
+            Box::new(Mbc5::new(rom_data, ram_size, true))
+        }
+        _ => {
+            println!(
+                "UNSUPPORTED cartridge type: {:#04X}, falling back to ROM ONLY",
+                rom_header.cartridge_type()
+            );
+            Box::new(RomOnly::new(rom_data, ram_size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mapper, MapperRegistry};
+    use crate::cartridge::cart::{tests::numbered_rom, RomHeader};
+
+    struct FixedByteMapper(u8);
+
+    impl Mapper for FixedByteMapper {
+        fn read_rom(&self, _address: u16) -> u8 {
+            self.0
+        }
+        fn write_rom(&mut self, _address: u16, value: u8) {
+            self.0 = value;
+        }
+        fn read_ram(&self, _address: u16) -> u8 {
+            0xFF
+        }
+        fn write_ram(&mut self, _address: u16, _value: u8) {}
+    }
+
+    fn rom_with_type(cartridge_type: u8) -> Vec<u8> {
+        let mut rom = numbered_rom(4);
+        rom[0x147] = cartridge_type;
+        rom
+    }
+
+    #[test]
+    fn builtin_mapper_from_cartridge_type() {
+        let rom = rom_with_type(0x01);
+        let header = RomHeader::new(&rom);
+        let mut mapper = MapperRegistry::new().create(rom, &header);
+
+        mapper.write_rom(0x2000, 3);
+        assert_eq!(mapper.read_rom(0x4000), 3);
+    }
+
+    #[test]
+    fn registered_mapper_takes_precedence() {
+        let mut registry = MapperRegistry::new();
+        registry.register(0x01, |_, _| Box::new(FixedByteMapper(0x42)));
+        registry.register(0xEE, |_, _| Box::new(FixedByteMapper(0x24)));
+
+        let rom = rom_with_type(0x01);
+        let header = RomHeader::new(&rom);
+        let mut mapper = registry.create(rom, &header);
+        assert_eq!(mapper.read_rom(0x4000), 0x42);
+        mapper.write_rom(0x2000, 3);
+        assert_eq!(mapper.read_rom(0x0000), 3);
+
+        let rom = rom_with_type(0xEE);
+        let header = RomHeader::new(&rom);
+        assert_eq!(registry.create(rom, &header).read_rom(0x0000), 0x24);
+    }
+}
@@ -0,0 +1,250 @@
+use super::cart::{load_ram, read_ram_bank, read_rom_bank, write_ram_bank, ROM_BANK_SIZE};
+use super::mapper::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/*
+ 0000-1FFF  RAM enable      (0x0A in the lower nibble enables external RAM)
+ 2000-3FFF  ROM bank number (5 bits, 0 is translated to 1)
+ 4000-5FFF  RAM bank number or upper bits of the ROM bank number (2 bits)
+ 6000-7FFF  Banking mode    (0=simple, 1=advanced)
+*/
+
+pub struct Mbc1 {
+    rom_data: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    advanced_mode: bool,
+    // MBC1M multicarts only wire 4 bits of BANK1, so BANK2 selects a 256 KiB game
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom_data: Vec<u8>, ram_size: usize) -> Self {
+        let multicart = Mbc1::is_multicart(&rom_data);
+        Self {
+            rom_data,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_mode: false,
+            multicart,
+        }
+    }
+
+    /// MBC1M carts are 1 MiB and repeat the Nintendo logo in the header of
+    /// every 256 KiB game (bank 0x10, 0x20 and 0x30).
+    fn is_multicart(rom_data: &[u8]) -> bool {
+        if rom_data.len() != 64 * ROM_BANK_SIZE {
+            return false;
+        }
+        let logo = &rom_data[0x104..0x134];
+        let games = (1..4)
+            .filter(|game| {
+                let header = game * 0x10 * ROM_BANK_SIZE + 0x104;
+                &rom_data[header..header + 0x30] == logo
+            })
+            .count();
+        games > 0
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn bank1_mask(&self) -> u8 {
+        if self.multicart {
+            0x0F
+        } else {
+            0x1F
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            if self.advanced_mode {
+                (self.bank2 as usize) << self.bank2_shift()
+            } else {
+                0
+            }
+        } else {
+            self.rom_bank()
+        };
+        read_rom_bank(&self.rom_data, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.bank1 = value & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0b11,
+            _ => self.advanced_mode = value & 1 == 1,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        read_ram_bank(&self.ram, self.ram_bank(), address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        let bank = self.ram_bank();
+        write_ram_bank(&mut self.ram, bank, address, value);
+    }
+
+    fn rom_bank(&self) -> usize {
+        ((self.bank2 as usize) << self.bank2_shift()) | (self.bank1 & self.bank1_mask()) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.bank1);
+        writer.write_u8(self.bank2);
+        writer.write_bool(self.advanced_mode);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.bank1 = reader.read_u8()?;
+        self.bank2 = reader.read_u8()?;
+        self.advanced_mode = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mbc1;
+    use crate::cartridge::cart::{tests::numbered_rom, ROM_BANK_SIZE};
+    use crate::cartridge::mapper::Mapper;
+    use crate::savestate::{StateReader, StateWriter};
+
+    #[test]
+    fn rom_bank_zero_maps_to_one() {
+        let mut mbc = Mbc1::new(numbered_rom(8), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 5);
+        assert_eq!(mbc.read_rom(0x7FFF), 5);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn rom_bank_wraps_to_rom_size() {
+        let mut mbc = Mbc1::new(numbered_rom(4), 0);
+        mbc.write_rom(0x2000, 6);
+        assert_eq!(mbc.read_rom(0x4000), 2);
+    }
+
+    #[test]
+    fn upper_rom_bits_and_advanced_mode() {
+        let mut mbc = Mbc1::new(numbered_rom(128), 0);
+        mbc.write_rom(0x2000, 0x03);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(0x4000), 0x43);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+
+        mbc.write_rom(0x6000, 1);
+        assert_eq!(mbc.read_rom(0x0000), 0x40);
+    }
+
+    #[test]
+    fn ram_enable_and_banking() {
+        let mut mbc = Mbc1::new(numbered_rom(4), 0x8000);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+
+        mbc.write_rom(0x6000, 1);
+        mbc.write_rom(0x4000, 2);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_ram(0xA000, 0x24);
+
+        mbc.write_rom(0x4000, 0);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut mbc = Mbc1::new(numbered_rom(128), 0x8000);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_ram(0xA123, 0x99);
+
+        let mut writer = StateWriter::new();
+        mbc.save_state(&mut writer);
+        let data = writer.into_bytes();
+
+        let mut restored = Mbc1::new(numbered_rom(128), 0x8000);
+        let mut reader = StateReader::new(&data).unwrap();
+        restored.load_state(&mut reader).unwrap();
+        assert_eq!(restored.read_rom(0x4000), 0x25);
+        assert_eq!(restored.read_rom(0x0000), 0x20);
+        assert_eq!(restored.read_ram(0xA123), 0x99);
+    }
+
+    #[test]
+    fn multicart_wiring() {
+        let mut rom = numbered_rom(64);
+        for game in 0..4 {
+            let header = game * 0x10 * ROM_BANK_SIZE + 0x104;
+            for (i, byte) in rom[header..header + 0x30].iter_mut().enumerate() {
+                *byte = i as u8 ^ 0xCE;
+            }
+        }
+        let mut mbc = Mbc1::new(rom, 0);
+        assert!(mbc.multicart);
+
+        mbc.write_rom(0x4000, 1);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+
+        mbc.write_rom(0x6000, 1);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+    }
+}
@@ -0,0 +1,138 @@
+use super::cart::{load_ram, read_rom_bank};
+use super::mapper::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/*
+ 0000-3FFF  Address bit 8 selects the register:
+              bit 8 clear: RAM enable (0x0A in the lower nibble)
+              bit 8 set:   ROM bank number (4 bits, 0 is translated to 1)
+ A000-A1FF  Built-in 512x4 bits RAM, only the lower nibble is stored
+ A200-BFFF  Echoes of A000-A1FF
+*/
+
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+pub struct Mbc2 {
+    rom_data: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom_data: Vec<u8>) -> Self {
+        Self {
+            rom_data,
+            ram: vec![0; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        read_rom_bank(&self.rom_data, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if address >= 0x4000 {
+            return;
+        }
+        if address & 0x100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = value & 0x0F;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // the upper nibble isn't connected and reads as 1s
+        self.ram[address as usize & (MBC2_RAM_SIZE - 1)] | 0xF0
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        self.ram[address as usize & (MBC2_RAM_SIZE - 1)] = value & 0x0F;
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mbc2;
+    use crate::cartridge::cart::tests::numbered_rom;
+    use crate::cartridge::mapper::Mapper;
+
+    #[test]
+    fn register_selected_by_address_bit_8() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+
+        // bit 8 clear, this is the RAM enable register, not the ROM bank
+        mbc.write_rom(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_ram(0xA000, 0x05);
+        assert_eq!(mbc.read_ram(0xA000), 0xF5);
+
+        mbc.write_rom(0x2100, 0x07);
+        assert_eq!(mbc.read_rom(0x4000), 7);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x3FFF, 0x1F);
+        assert_eq!(mbc.read_rom(0x4000), 0x0F);
+    }
+
+    #[test]
+    fn nibble_ram_is_mirrored() {
+        let mut mbc = Mbc2::new(numbered_rom(2));
+        mbc.write_rom(0x0000, 0x0A);
+
+        mbc.write_ram(0xA1FF, 0xAB);
+        assert_eq!(mbc.read_ram(0xA1FF), 0xFB);
+        assert_eq!(mbc.read_ram(0xA3FF), 0xFB);
+        assert_eq!(mbc.read_ram(0xBFFF), 0xFB);
+
+        mbc.write_ram(0xB200, 0x03);
+        assert_eq!(mbc.read_ram(0xA000), 0xF3);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+}
@@ -0,0 +1,204 @@
+use super::cart::{load_ram, read_ram_bank, read_rom_bank, write_ram_bank};
+use super::mapper::Mapper;
+use super::rtc::Rtc;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/*
+ 0000-1FFF  RAM and timer enable (0x0A in the lower nibble)
+ 2000-3FFF  ROM bank number (7 bits, 0 is translated to 1)
+ 4000-5FFF  RAM bank number (00-03) or RTC register select (08-0C)
+ 6000-7FFF  Latch clock data (write 0x00 then 0x01)
+*/
+
+pub struct Mbc3 {
+    rom_data: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    // RAM bank, or an RTC register when 0x08-0x0C
+    ram_bank: u8,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(rom_data: Vec<u8>, ram_size: usize, rtc: Option<Rtc>) -> Self {
+        Self {
+            rom_data,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc,
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        read_rom_bank(&self.rom_data, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (0x00..=0x07, _) => read_ram_bank(&self.ram, self.ram_bank as usize, address),
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+            (0x00..=0x07, _) => {
+                write_ram_bank(&mut self.ram, self.ram_bank as usize, address, value)
+            }
+            (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+            _ => {}
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    /// External RAM followed by the clock block when the cartridge has one.
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.save_block());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+        if let Some(rtc) = &mut self.rtc {
+            if data.len() > self.ram.len() {
+                rtc.load_block(&data[self.ram.len()..]);
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rtc.is_some());
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        self.ram_bank = reader.read_u8()?;
+        if reader.read_bool()? {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.load_state(reader)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mbc3;
+    use crate::cartridge::cart::tests::numbered_rom;
+    use crate::cartridge::mapper::Mapper;
+    use crate::cartridge::rtc::{FixedClock, Rtc, RTC_SAVE_SIZE};
+
+    #[test]
+    fn rom_banking_7_bits() {
+        let mut mbc = Mbc3::new(numbered_rom(128), 0, None);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        assert_eq!(mbc.read_rom(0x3FFF), 0);
+    }
+
+    #[test]
+    fn ram_and_rtc_registers_share_the_window() {
+        let clock = FixedClock::new(0);
+        let rtc = Rtc::new(Box::new(clock.clone()));
+        let mut mbc = Mbc3::new(numbered_rom(4), 0x8000, Some(rtc));
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0xA000, 0x33);
+        assert_eq!(mbc.read_ram(0xA000), 0x33);
+
+        clock.advance(42);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 42);
+
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_ram(0xA000), 0x33);
+    }
+
+    #[test]
+    fn save_data_has_trailing_rtc_block() {
+        let clock = FixedClock::new(0);
+        let rtc = Rtc::new(Box::new(clock.clone()));
+        let mut mbc = Mbc3::new(numbered_rom(4), 0x2000, Some(rtc));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA010, 0x77);
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_ram(0xA000, 12);
+
+        let save = mbc.save_data();
+        assert_eq!(save.len(), 0x2000 + RTC_SAVE_SIZE);
+        assert_eq!(save[0x10], 0x77);
+        assert_eq!(save[0x2000 + 4], 12);
+
+        let rtc = Rtc::new(Box::new(clock.clone()));
+        let mut restored = Mbc3::new(numbered_rom(4), 0x2000, Some(rtc));
+        restored.load_save_data(&save);
+        restored.write_rom(0x0000, 0x0A);
+        restored.write_rom(0x6000, 0x00);
+        restored.write_rom(0x6000, 0x01);
+        restored.write_rom(0x4000, 0x09);
+        assert_eq!(restored.read_ram(0xA000), 12);
+        restored.write_rom(0x4000, 0x00);
+        assert_eq!(restored.read_ram(0xA010), 0x77);
+    }
+}
@@ -0,0 +1,190 @@
+use super::cart::{load_ram, read_ram_bank, read_rom_bank, write_ram_bank};
+use super::mapper::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/*
+ 0000-1FFF  RAM enable (0x0A in the lower nibble)
+ 2000-2FFF  Lower 8 bits of the ROM bank number (0 is a valid bank)
+ 3000-3FFF  9th bit of the ROM bank number
+ 4000-5FFF  RAM bank number (4 bits), on rumble carts bit 3 drives the motor
+*/
+
+/// Called with the new motor state every time a rumble cart switches it.
+pub type RumbleCallback = Box<dyn FnMut(bool)>;
+
+pub struct Mbc5 {
+    rom_data: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble_on: bool,
+    rumble_callback: Option<RumbleCallback>,
+}
+
+impl Mbc5 {
+    pub fn new(rom_data: Vec<u8>, ram_size: usize, has_rumble: bool) -> Self {
+        Self {
+            rom_data,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble_on: false,
+            rumble_callback: None,
+        }
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.rumble_on
+    }
+
+    fn set_rumble(&mut self, rumble_on: bool) {
+        if self.rumble_on == rumble_on {
+            return;
+        }
+        self.rumble_on = rumble_on;
+        if let Some(callback) = &mut self.rumble_callback {
+            callback(rumble_on);
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        read_rom_bank(&self.rom_data, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 1) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.ram_bank = value & 0x07;
+                    self.set_rumble(value & 0x08 != 0);
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        read_ram_bank(&self.ram, self.ram_bank as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        write_ram_bank(&mut self.ram, self.ram_bank as usize, address, value);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rumble_on);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        let rumble_on = reader.read_bool()?;
+        self.set_rumble(rumble_on);
+        Ok(())
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::mapper::Mapper;
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::Mbc5;
+    use crate::cartridge::cart::tests::numbered_rom;
+
+    #[test]
+    fn rom_bank_9_bits() {
+        let mut rom = numbered_rom(512);
+        // numbered_rom truncates the bank number to a byte, tag bank 0x101 explicitly
+        rom[0x101 * 0x4000] = 0xAB;
+        let mut mbc = Mbc5::new(rom, 0, false);
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4001), 0x00);
+
+        mbc.write_rom(0x2000, 0x01);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0xAB);
+
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+    }
+
+    #[test]
+    fn ram_banking_128k() {
+        let mut mbc = Mbc5::new(numbered_rom(4), 0x20000, false);
+        mbc.write_rom(0x0000, 0x0A);
+        for bank in 0..16 {
+            mbc.write_rom(0x4000, bank);
+            mbc.write_ram(0xBFFF, bank);
+        }
+        for bank in 0..16 {
+            mbc.write_rom(0x4000, bank);
+            assert_eq!(mbc.read_ram(0xBFFF), bank);
+        }
+    }
+
+    #[test]
+    fn rumble_motor_callback() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut mbc = Mbc5::new(numbered_rom(4), 0x8000, true);
+        let recorder = events.clone();
+        mbc.set_rumble_callback(Box::new(move |on| recorder.borrow_mut().push(on)));
+
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_rom(0x4000, 0x0B);
+        mbc.write_rom(0x4000, 0x01);
+
+        assert_eq!(*events.borrow(), vec![true, false]);
+        assert!(!mbc.is_rumbling());
+        assert_eq!(mbc.ram_bank, 1);
+    }
+}
@@ -1,7 +1,15 @@
 // use rom_types::RomTypes;
 // use licensee_code::LicenseeCode;
 pub use cart::Cartridge;
+pub use mapper::{Mapper, MapperRegistry};
 
 pub mod rom_types;
 pub mod licensee_code;
-pub mod cart;
\ No newline at end of file
+pub mod cart;
+pub mod mapper;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rom_only;
+pub mod rtc;
@@ -0,0 +1,60 @@
+use super::cart::{load_ram, read_rom_bank, RAM_BANK_SIZE};
+use super::mapper::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Plain 32 KiB cartridges without a memory bank controller, optionally with
+/// up to 8 KiB of unbanked external RAM.
+pub struct RomOnly {
+    rom_data: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom_data: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            rom_data,
+            ram: vec![0; ram_size.min(RAM_BANK_SIZE)],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = (address as usize) >> 14;
+        read_rom_bank(&self.rom_data, bank, address)
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram.is_empty() {
+            return 0xFF;
+        }
+        let offset = (address as usize - 0xA000) % self.ram.len();
+        self.ram[offset]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram.is_empty() {
+            return;
+        }
+        let offset = (address as usize - 0xA000) % self.ram.len();
+        self.ram[offset] = value;
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.ram)
+    }
+}
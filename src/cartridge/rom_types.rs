@@ -1,3 +1,6 @@
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RomTypes {
     RomOnly = 0x00,
     MBC1 = 0x01,
@@ -27,4 +30,62 @@ pub enum RomTypes {
     BandaiTama5 = 0xFD,
     HuC3 = 0xFE,
     HuC1RamBattery = 0xFF,
-}
\ No newline at end of file
+}
+
+impl RomTypes {
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            RomTypes::Mbc1RamBattery
+                | RomTypes::Mbc2Battery
+                | RomTypes::RomRamBattery1
+                | RomTypes::Mmm01RamBattery
+                | RomTypes::Mbc3TimerBattery
+                | RomTypes::Mbc3TimerRamBattery2
+                | RomTypes::Mbc3RamBattery2
+                | RomTypes::Mbc5RamBattery
+                | RomTypes::Mbc5RumbleRamBattery
+                | RomTypes::Mbc7SensorRumbleRamBattery
+                | RomTypes::HuC3
+                | RomTypes::HuC1RamBattery
+        )
+    }
+}
+
+impl TryFrom<u8> for RomTypes {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(RomTypes::RomOnly),
+            0x01 => Ok(RomTypes::MBC1),
+            0x02 => Ok(RomTypes::Mbc1Ram),
+            0x03 => Ok(RomTypes::Mbc1RamBattery),
+            0x05 => Ok(RomTypes::MBC2),
+            0x06 => Ok(RomTypes::Mbc2Battery),
+            0x08 => Ok(RomTypes::RomRam1),
+            0x09 => Ok(RomTypes::RomRamBattery1),
+            0x0B => Ok(RomTypes::MMM01),
+            0x0C => Ok(RomTypes::Mmm01Ram),
+            0x0D => Ok(RomTypes::Mmm01RamBattery),
+            0x0F => Ok(RomTypes::Mbc3TimerBattery),
+            0x10 => Ok(RomTypes::Mbc3TimerRamBattery2),
+            0x11 => Ok(RomTypes::MBC3),
+            0x12 => Ok(RomTypes::Mbc3Ram2),
+            0x13 => Ok(RomTypes::Mbc3RamBattery2),
+            0x19 => Ok(RomTypes::MBC5),
+            0x1A => Ok(RomTypes::Mbc5Ram),
+            0x1B => Ok(RomTypes::Mbc5RamBattery),
+            0x1C => Ok(RomTypes::Mbc5Rumble),
+            0x1D => Ok(RomTypes::Mbc5RumbleRam),
+            0x1E => Ok(RomTypes::Mbc5RumbleRamBattery),
+            0x20 => Ok(RomTypes::MBC6),
+            0x22 => Ok(RomTypes::Mbc7SensorRumbleRamBattery),
+            0xFC => Ok(RomTypes::PocketCamera),
+            0xFD => Ok(RomTypes::BandaiTama5),
+            0xFE => Ok(RomTypes::HuC3),
+            0xFF => Ok(RomTypes::HuC1RamBattery),
+            _ => Err(()),
+        }
+    }
+}
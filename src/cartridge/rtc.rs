@@ -0,0 +1,336 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/*
+ 08  RTC S   Seconds   0-59 (0-3Bh)
+ 09  RTC M   Minutes   0-59 (0-3Bh)
+ 0A  RTC H   Hours     0-23 (0-17h)
+ 0B  RTC DL  Lower 8 bits of Day Counter (0-FFh)
+ 0C  RTC DH  Upper 1 bit of Day Counter, Carry Bit, Halt Flag
+       Bit 0  Most significant bit of Day Counter (Bit 8)
+       Bit 6  Halt (0=Active, 1=Stop Timer)
+       Bit 7  Day Counter Carry Bit (1=Counter Overflow)
+*/
+
+/// Source of the current time in seconds, the RTC advances by the difference
+/// between two readings.
+pub trait ClockSource {
+    fn now(&self) -> u64;
+}
+
+/// Host wall time.
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Clock that only moves when told to, clones share the same time.
+#[derive(Clone, Default)]
+pub struct FixedClock {
+    seconds: Rc<Cell<u64>>,
+}
+
+impl FixedClock {
+    pub fn new(seconds: u64) -> Self {
+        Self {
+            seconds: Rc::new(Cell::new(seconds)),
+        }
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.seconds.set(self.seconds.get() + seconds);
+    }
+}
+
+impl ClockSource for FixedClock {
+    fn now(&self) -> u64 {
+        self.seconds.get()
+    }
+}
+
+/// Size of the clock block appended to MBC3 save files (BGB / VBA-M layout):
+/// the 5 clock registers then the 5 latched registers as little-endian u32,
+/// followed by the UNIX timestamp of the save as a little-endian u64.
+pub const RTC_SAVE_SIZE: usize = 48;
+
+// older VBA-M versions stored the timestamp on 32 bits
+const RTC_SAVE_SIZE_32BIT_TIMESTAMP: usize = 44;
+
+#[derive(Clone, Copy, Default)]
+struct RtcCounters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,
+}
+
+impl RtcCounters {
+    fn advance(&mut self, elapsed: u64) {
+        if self.halt || elapsed == 0 {
+            return;
+        }
+
+        let total_seconds = self.seconds as u64 + elapsed;
+        self.seconds = (total_seconds % 60) as u8;
+
+        let total_minutes = self.minutes as u64 + total_seconds / 60;
+        self.minutes = (total_minutes % 60) as u8;
+
+        let total_hours = self.hours as u64 + total_minutes / 60;
+        self.hours = (total_hours % 24) as u8;
+
+        let total_days = self.days as u64 + total_hours / 24;
+        if total_days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (total_days & 0x1FF) as u16;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            self.day_high(),
+        ]
+    }
+
+    fn day_high(&self) -> u8 {
+        ((self.days >> 8) as u8 & 1) | ((self.halt as u8) << 6) | ((self.carry as u8) << 7)
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 1) as u16) << 8);
+                self.halt = value & 0x40 != 0;
+                self.carry = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct Rtc {
+    clock: Box<dyn ClockSource>,
+    last_update: u64,
+    counters: RtcCounters,
+    latched: [u8; 5],
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn ClockSource>) -> Self {
+        let last_update = clock.now();
+        Self {
+            clock,
+            last_update,
+            counters: RtcCounters::default(),
+            latched: [0; 5],
+            latch_armed: false,
+        }
+    }
+
+    /// Catches the counters up with the clock source.
+    pub fn update(&mut self) {
+        let now = self.clock.now();
+        self.counters.advance(now.saturating_sub(self.last_update));
+        self.last_update = now;
+    }
+
+    /// Writing 0x00 then 0x01 to 6000-7FFF copies the counters into the latch.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.counters.registers();
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.latched[0] & 0x3F,
+            0x09 => self.latched[1] & 0x3F,
+            0x0A => self.latched[2] & 0x1F,
+            0x0B => self.latched[3],
+            0x0C => self.latched[4] & 0xC1,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        self.counters.write(register, value);
+    }
+
+    pub fn save_block(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        let mut counters = self.counters;
+        counters.advance(now.saturating_sub(self.last_update));
+
+        let mut block = Vec::with_capacity(RTC_SAVE_SIZE);
+        for register in counters.registers().iter().chain(self.latched.iter()) {
+            block.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        block.extend_from_slice(&now.to_le_bytes());
+        block
+    }
+
+    /// Restores the clock from a save file block, the time spent while the
+    /// emulator was closed is added on the next update.
+    pub fn load_block(&mut self, block: &[u8]) {
+        let timestamp = match block.len() {
+            RTC_SAVE_SIZE => u64::from_le_bytes(block[40..48].try_into().unwrap()),
+            RTC_SAVE_SIZE_32BIT_TIMESTAMP => {
+                u32::from_le_bytes(block[40..44].try_into().unwrap()) as u64
+            }
+            _ => {
+                println!("ignoring RTC save block of {} bytes", block.len());
+                return;
+            }
+        };
+
+        let register = |index: usize| block[index * 4];
+        self.counters = RtcCounters::default();
+        for (index, address) in (0x08..=0x0C).enumerate() {
+            self.counters.write(address, register(index));
+            self.latched[index] = register(index + 5);
+        }
+        self.last_update = timestamp.min(self.clock.now());
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.save_block());
+        writer.write_bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let block = reader.read_bytes()?;
+        self.load_block(block);
+        self.latch_armed = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixedClock, Rtc, RTC_SAVE_SIZE};
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn rtc_counts_from_clock_source() {
+        let clock = FixedClock::new(1_000);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+        clock.advance(59);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 59);
+        assert_eq!(rtc.read(0x09), 0);
+
+        clock.advance(1 + 60 * 60 + 24 * 60 * 60);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 1);
+        assert_eq!(rtc.read(0x0A), 1);
+        assert_eq!(rtc.read(0x0B), 1);
+        assert_eq!(rtc.read(0x0C), 0);
+    }
+
+    #[test]
+    fn rtc_reads_are_latched() {
+        let clock = FixedClock::new(0);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+        clock.advance(10);
+        latch(&mut rtc);
+        clock.advance(10);
+        assert_eq!(rtc.read(0x08), 10);
+
+        // 0x01 without a preceding 0x00 doesn't latch
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 10);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 20);
+    }
+
+    #[test]
+    fn rtc_halt_stops_the_clock() {
+        let clock = FixedClock::new(0);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+        rtc.write(0x0C, 0x40);
+        clock.advance(100);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x0C), 0x40);
+
+        rtc.write(0x0C, 0x00);
+        clock.advance(5);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 5);
+    }
+
+    #[test]
+    fn rtc_save_block_round_trip() {
+        let clock = FixedClock::new(1_000_000);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(0x0A, 5);
+        clock.advance(30);
+        latch(&mut rtc);
+
+        let block = rtc.save_block();
+        assert_eq!(block.len(), RTC_SAVE_SIZE);
+        assert_eq!(block[0], 30);
+        assert_eq!(block[8], 5);
+        assert_eq!(block[20], 30);
+        assert_eq!(&block[40..48], &1_000_030u64.to_le_bytes());
+
+        // the emulator was closed for an hour
+        clock.advance(60 * 60);
+        let mut restored = Rtc::new(Box::new(clock.clone()));
+        restored.load_block(&block);
+        assert_eq!(restored.read(0x08), 30);
+
+        latch(&mut restored);
+        assert_eq!(restored.read(0x08), 30);
+        assert_eq!(restored.read(0x0A), 6);
+    }
+
+    #[test]
+    fn rtc_day_counter_overflow_sets_carry() {
+        let clock = FixedClock::new(0);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        clock.advance(24 * 60 * 60);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), 0x80);
+    }
+}
@@ -1,7 +1,12 @@
 use core::panic;
+use std::fs;
+use std::path::Path;
 
 use crate::bus::Bus;
 use crate::cpu::util::add_relative;
+use crate::io::Button;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::watchpoint::{Access, WatchHit, Watchpoints};
 
 use super::instruction::ConditionType;
 use super::instruction::InstructionType;
@@ -33,22 +38,31 @@ pub struct CpuContext<'a> {
     pub last_written_address: Option<u16>,
     pub dma_done: bool,
 
-    ly: u8,
+    pub watchpoints: Watchpoints,
 }
 
 impl<'a> CpuContext<'a> {
     pub fn new(rom_file: &'a str, instruction_set: &'a InstructionSet) -> Self {
-        let bus = Bus::new(rom_file);
+        CpuContext::with_bus(Bus::new(rom_file), instruction_set)
+    }
+
+    pub fn with_bus(bus: Bus<'a>, instruction_set: &'a InstructionSet) -> Self {
+        let cpu_registers = if bus.boot_rom_mapped() {
+            CpuRegisters::power_on()
+        } else {
+            let header_checksum = bus.cartridge().header().header_checksum();
+            CpuRegisters::post_boot(bus.model(), bus.cgb_mode(), header_checksum)
+        };
         Self {
+            old_pc: cpu_registers.pc,
             bus,
             instruction_set,
-            cpu_registers: CpuRegisters::new(),
+            cpu_registers,
             current_instruction: instruction_set.get_instruction_by_opcode(0x00),
             fetched_data: FetchedData {
                 source: ValueEnum::None,
                 destination: DestinationEnum::None,
             },
-            old_pc: 0x100,
             current_opcode: 0,
             halted: false,
             // stepping: false,
@@ -58,8 +72,55 @@ impl<'a> CpuContext<'a> {
             last_written_address: None,
 
             dma_done: false,
-            ly: 0,
+
+            watchpoints: Watchpoints::new(),
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn interrupt_master_enabled(&self) -> bool {
+        self.interrupt_master_enabled
+    }
+
+    /// Snapshot of the whole machine, see `savestate` for the format.
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Leaves the machine untouched when the snapshot can't be loaded.
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(data)?;
+        let backup = self.save_snapshot();
+        if let Err(e) = self.load_state(&mut reader) {
+            // older snapshots check the ROM late and any snapshot can break off
+            // half way, go back to the state before the load
+            self.load_state(&mut StateReader::new(&backup)?)?;
+            return Err(e);
         }
+        Ok(())
+    }
+
+    pub fn save_snapshot_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        fs::write(path, self.save_snapshot())?;
+        Ok(())
+    }
+
+    pub fn load_snapshot_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        let data = fs::read(path)?;
+        self.load_snapshot(&data)
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.bus.io.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.bus.io.release(button);
     }
 
     fn get_interrupt_enable_register(&self) -> u8 {
@@ -82,7 +143,6 @@ impl<'a> CpuContext<'a> {
     fn interrupt_handle(&mut self, address: u16) {
         // Do not count the CPU ticks during interrupts!
         // self.stack_push16(self.cpu_registers.pc);
-        println!("Running interrupt handler for address: {:04X}", address);
         self.cpu_registers.sp -= 1;
         self.bus
             .bus_write8(self.cpu_registers.sp, (self.cpu_registers.pc >> 8) as u8);
@@ -97,7 +157,7 @@ impl<'a> CpuContext<'a> {
 
         let interrupt_enable = self.get_interrupt_enable_register();
 
-        let allowed_interrupts = interrupt_flags & interrupt_enable;
+        let allowed_interrupts = interrupt_flags & interrupt_enable & 0x1F;
 
         if allowed_interrupts == 0 {
             return;
@@ -130,33 +190,32 @@ impl<'a> CpuContext<'a> {
         self.cpu_registers.set_register(register_type, value);
     }
 
-    fn return_and_inc_ly(&mut self) -> u8 {
-        let ly = self.ly;
-        self.ly += 1;
-        ly
-    }
-
     pub fn bus_read(&mut self, address: u16) -> u8 {
         self.emu_cycles(1);
-        if address == 0xFF44 {
-            self.return_and_inc_ly()
-        } else {
-            self.bus.bus_read(address)
-        }
+        let value = self.bus.bus_read(address);
+        self.check_watchpoints(address, Access::Read, value, value);
+        value
     }
 
     pub fn bus_read16(&mut self, address: u16) -> u16 {
         self.emu_cycles(2);
-        self.bus.bus_read16(address)
+        let value = self.bus.bus_read16(address);
+        self.check_watchpoints(address, Access::Read, value as u8, value as u8);
+        let hi = (value >> 8) as u8;
+        self.check_watchpoints(address.wrapping_add(1), Access::Read, hi, hi);
+        value
     }
 
     pub fn bus_write(&mut self, address: u16, value: ValueEnum) {
         match value {
             ValueEnum::Data8(data) => {
+                self.check_write_watchpoints(address, data);
                 self.bus.bus_write8(address, data);
                 self.emu_cycles(1);
             }
             ValueEnum::Data16(data) => {
+                self.check_write_watchpoints(address, data as u8);
+                self.check_write_watchpoints(address.wrapping_add(1), (data >> 8) as u8);
                 self.bus.bus_write16(address, data);
                 self.emu_cycles(2);
             }
@@ -167,6 +226,26 @@ impl<'a> CpuContext<'a> {
         self.last_written_address = Some(address);
     }
 
+    fn check_write_watchpoints(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            let old_value = self.bus.bus_read(address);
+            self.check_watchpoints(address, Access::Write, old_value, value);
+        }
+    }
+
+    fn check_watchpoints(&mut self, address: u16, access: Access, old_value: u8, new_value: u8) {
+        if self.watchpoints.matches(address, access, new_value) {
+            self.watchpoints.record_hit(WatchHit {
+                pc: self.old_pc,
+                address,
+                access,
+                old_value,
+                new_value,
+                bank: self.bus.bank_at(address),
+            });
+        }
+    }
+
     pub fn stack_push(&mut self, data: u8) {
         self.cpu_registers.sp -= 1;
         self.bus_write(self.cpu_registers.sp, ValueEnum::Data8(data));
@@ -253,6 +332,8 @@ impl<'a> CpuContext<'a> {
         self.dma_done = false;
         self.last_written_address = None;
 
+        self.hdma_step();
+
         self.old_pc = self.cpu_registers.pc;
         if !self.halted {
             self.fetch_instruction();
@@ -263,7 +344,10 @@ impl<'a> CpuContext<'a> {
             self.execute();
         } else {
             self.emu_cycles(1);
-            if self.get_interrupt_flags_register() != 0 {
+            // an enabled interrupt ends HALT even with IME off
+            let pending =
+                self.get_interrupt_flags_register() & self.get_interrupt_enable_register();
+            if pending & 0x1F != 0 {
                 self.halted = false;
             }
         }
@@ -284,16 +368,44 @@ impl<'a> CpuContext<'a> {
         self.cpu_registers.pc += 1;
         value
     }
+    /// Copies the VRAM DMA blocks that are due, the CPU is stopped for 8
+    /// M-cycles per block, 16 in double speed.
+    fn hdma_step(&mut self) {
+        while self.bus.hdma.block_pending() {
+            self.bus.hdma_copy_block();
+            let cycles = if self.bus.double_speed() { 16 } else { 8 };
+            self.emu_cycles(cycles);
+        }
+    }
+
+    /// Runs `ticks` M-cycles of the CPU. In CGB double speed the CPU, the
+    /// timer and OAM DMA run twice as fast while the PPU and APU keep their
+    /// pace, `ticks` counts the dots of the PPU clock in both speeds.
     fn emu_cycles(&mut self, ticks: usize) {
         for _ in 0..ticks {
-            for _ in 0..4 {
-                self.ticks += 1;
-
+            let double_speed = self.bus.double_speed();
+            for cycle in 0..4 {
                 if let Some(interrupt) = self.bus.io.timer.timer_tick() {
                     self.request_interrupt(interrupt);
                 }
+                if double_speed && cycle & 1 == 1 {
+                    continue;
+                }
+                self.ticks += 1;
+
+                // the frame sequencer follows DIV bit 13 in double speed
+                let div = self.bus.io.timer.div() >> double_speed as u8;
+                self.bus.apu.apu_tick(div);
+
+                let ppu_interrupts = self.bus.ppu.ppu_tick();
+                if ppu_interrupts != 0 {
+                    let interrupt_flags = self.get_interrupt_flags_register();
+                    self.set_interrupt_flags_register(interrupt_flags | ppu_interrupts);
+                }
+                self.bus.hdma_tick();
             }
             self.dma_done = self.bus.dma_tick();
+            self.bus.cart_tick(if double_speed { 2 } else { 4 });
         }
     }
 
@@ -493,7 +605,10 @@ impl<'a> CpuContext<'a> {
             InstructionType::NONE => self.process_none(),
             InstructionType::NOP => self.process_nop(),
             InstructionType::STOP => {
-                panic!("Stopping!");
+                // on CGB, STOP is how the speed switch armed in KEY1 happens
+                if !self.bus.switch_speed() {
+                    panic!("Stopping!");
+                }
             }
 
             //InstructionType::LD | InstructionType::LDH => self.process_ld(),
@@ -1180,6 +1295,41 @@ impl<'a> CpuContext<'a> {
     }
 }
 
+impl SaveState for CpuContext<'_> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.bus.cartridge().save_rom_identity(writer);
+        self.cpu_registers.save_state(writer);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.interrupt_master_enabled);
+        writer.write_bool(self.enabling_ime);
+        writer.write_u64(self.ticks as u64);
+        writer.write_u16(self.old_pc);
+        writer.write_u8(self.current_opcode);
+        self.bus.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        if reader.version >= 9 {
+            self.bus.cartridge().check_rom_identity(reader)?;
+        }
+        self.cpu_registers.load_state(reader)?;
+        self.halted = reader.read_bool()?;
+        self.interrupt_master_enabled = reader.read_bool()?;
+        self.enabling_ime = reader.read_bool()?;
+        self.ticks = reader.read_u64()? as usize;
+        self.old_pc = reader.read_u16()?;
+        self.current_opcode = reader.read_u8()?;
+        self.current_instruction = self
+            .instruction_set
+            .get_instruction_by_opcode(self.current_opcode);
+        if reader.version < 2 {
+            // fake LY counter, the PPU owns LY since version 2
+            reader.read_u8()?;
+        }
+        self.bus.load_state(reader)
+    }
+}
+
 const REGISTERS_LOOKUP: [RegisterType; 8] = [
     RegisterType::B,
     RegisterType::C,
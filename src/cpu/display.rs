@@ -110,3 +110,28 @@ impl<'a> Display for CpuContext<'a> {
         )
     }
 }
+
+impl<'a> CpuContext<'a> {
+    /// State before the instruction at PC, in the log format of gameboy-doctor.
+    pub fn doctor_trace(&self) -> String {
+        let registers = &self.cpu_registers;
+        let pc = registers.pc;
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            registers.f.value(),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            registers.sp,
+            pc,
+            self.bus.bus_read(pc),
+            self.bus.bus_read(pc.wrapping_add(1)),
+            self.bus.bus_read(pc.wrapping_add(2)),
+            self.bus.bus_read(pc.wrapping_add(3))
+        )
+    }
+}
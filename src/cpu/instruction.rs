@@ -105,6 +105,11 @@ impl<'i> Instruction<'i> {
             ..Default::default()
         }
     }
+
+    /// Bytes taken by the opcode and its immediate operands, 2 for the CB prefix.
+    pub fn length(&self) -> u16 {
+        1 + self.operand_1.immediate_length() + self.operand_2.immediate_length()
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -123,6 +128,16 @@ pub enum Operand {
     SpPlusR8,
 }
 
+impl Operand {
+    fn immediate_length(&self) -> u16 {
+        match self {
+            Operand::A8Indirect | Operand::D8 | Operand::R8 | Operand::SpPlusR8 => 1,
+            Operand::A16 | Operand::A16Indirect | Operand::D16 => 2,
+            _ => 0,
+        }
+    }
+}
+
 impl Default for Operand {
     fn default() -> Self {
         Operand::None
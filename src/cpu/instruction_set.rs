@@ -1,3 +1,5 @@
+use std::sync::OnceLock;
+
 use super::{
     instruction::{ConditionType, Operand},
     Instruction, InstructionType, RegisterType,
@@ -889,49 +891,49 @@ impl InstructionSet {
         );
 
         instructions[0x98] = Instruction::instruction_2_operands(
-            "SBC A B",
+            "SBC A,B",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::B),
         );
         instructions[0x99] = Instruction::instruction_2_operands(
-            "SBC A C",
+            "SBC A,C",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::C),
         );
         instructions[0x9A] = Instruction::instruction_2_operands(
-            "SBC A D",
+            "SBC A,D",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::D),
         );
         instructions[0x9B] = Instruction::instruction_2_operands(
-            "SBC A E",
+            "SBC A,E",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::E),
         );
         instructions[0x9C] = Instruction::instruction_2_operands(
-            "SBC A H",
+            "SBC A,H",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::H),
         );
         instructions[0x9D] = Instruction::instruction_2_operands(
-            "SBC A L",
+            "SBC A,L",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::L),
         );
         instructions[0x9E] = Instruction::instruction_2_operands(
-            "SBC A (HL)",
+            "SBC A,(HL)",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Indirect(RegisterType::HL),
         );
         instructions[0x9F] = Instruction::instruction_2_operands(
-            "SBC A A",
+            "SBC A,A",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::Register(RegisterType::A),
@@ -1164,10 +1166,10 @@ impl InstructionSet {
         );
 
         instructions[0xCB] =
-            Instruction::instruction_1_operand("LD B,(HL)", InstructionType::CB, Operand::D8);
+            Instruction::instruction_1_operand("PREFIX CB", InstructionType::CB, Operand::D8);
 
         instructions[0xCC] = Instruction::instruction_1_operand_with_condition(
-            "CB d8",
+            "CALL Z,a16",
             InstructionType::CALL,
             Operand::A16,
             ConditionType::Z,
@@ -1239,7 +1241,7 @@ impl InstructionSet {
             ConditionType::C,
         );
         instructions[0xDE] = Instruction::instruction_2_operands(
-            "SBC A d8",
+            "SBC A,d8",
             InstructionType::SBC,
             Operand::Register(RegisterType::A),
             Operand::D8,
@@ -1366,3 +1368,10 @@ impl InstructionSet {
         &self.instructions[opcode as usize]
     }
 }
+
+/// The decoding table never changes, it is built on first use and shared by
+/// every CPU.
+pub fn instruction_set() -> &'static InstructionSet {
+    static INSTRUCTION_SET: OnceLock<InstructionSet> = OnceLock::new();
+    INSTRUCTION_SET.get_or_init(InstructionSet::new)
+}
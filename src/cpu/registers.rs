@@ -2,6 +2,8 @@ use super::{
     instruction::RegisterType,
     util::{combine, ValueEnum},
 };
+use crate::model::Model;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 use std::fmt::{Display, Result as FmtResult};
 
 pub enum Flags {
@@ -23,6 +25,10 @@ impl FlagsRegister {
         }
     }
 
+    pub fn value(&self) -> u8 {
+        self.register
+    }
+
     pub fn get_flag(&self, flag: Flags) -> bool {
         let mask = 1 << (flag as u8);
         self.register & mask == mask
@@ -90,6 +96,39 @@ impl Display for CpuRegisters {
     }
 }
 
+impl SaveState for CpuRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in [
+            self.a,
+            self.f.register,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+        ] {
+            writer.write_u8(register);
+        }
+        writer.write_u16(self.pc);
+        writer.write_u16(self.sp);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.a = reader.read_u8()?;
+        self.f.register = reader.read_u8()? & 0xF0;
+        self.b = reader.read_u8()?;
+        self.c = reader.read_u8()?;
+        self.d = reader.read_u8()?;
+        self.e = reader.read_u8()?;
+        self.h = reader.read_u8()?;
+        self.l = reader.read_u8()?;
+        self.pc = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        Ok(())
+    }
+}
+
 impl CpuRegisters {
     pub fn new() -> Self {
         Self {
@@ -106,6 +145,53 @@ impl CpuRegisters {
         }
     }
 
+    /// Registers as the boot ROM of `model` leaves them when it jumps to
+    /// 0x100, games tell the hardware apart by A (and B on AGB).
+    pub fn post_boot(model: Model, cgb_mode: bool, header_checksum: u8) -> Self {
+        // DMG and MGB clear H and C when the header checksum is 0
+        let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let (a, f, b, c, d, e, h, l) = match model {
+            Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg => (0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb if cgb_mode => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C),
+            Model::Agb if cgb_mode => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Agb => (0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C),
+        };
+        let mut registers = Self {
+            a,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            ..CpuRegisters::new()
+        };
+        registers.f.register = f;
+        registers
+    }
+
+    /// Registers at power on, the boot ROM starts at 0x0000.
+    pub fn power_on() -> Self {
+        let mut registers = Self {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            pc: 0,
+            sp: 0,
+            ..CpuRegisters::new()
+        };
+        registers.f.register = 0;
+        registers
+    }
+
     pub fn set_flags(
         &mut self,
         z: Option<bool>,
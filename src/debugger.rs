@@ -0,0 +1,620 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, Write};
+
+use crate::bus::Bus;
+use crate::cpu::instruction::InstructionType;
+use crate::cpu::instruction_set::instruction_set;
+use crate::cpu::CpuContext;
+use crate::disassembler::disassemble_instruction;
+use crate::emulator::Emulator;
+use crate::watchpoint::{Access, WatchHit, Watchpoint};
+
+// instructions shown before PC when disassembling around it
+const HISTORY_LENGTH: usize = 4;
+const DEFAULT_DISASSEMBLY_LINES: usize = 10;
+const DEFAULT_DUMP_LENGTH: usize = 64;
+
+const HELP: &str = "\
+b <addr>        set a breakpoint
+d <addr>        delete a breakpoint
+bl              list the breakpoints
+w <addr>[-<end>] [r|w|rw] [=<value>]
+                watch reads and/or writes (default w), optionally of a value
+wd <n>          delete watchpoint n
+wl              list the watchpoints
+s [n]           step n instructions (default 1)
+n               step over CALL and RST
+out             run until the current function returns
+c               continue until a breakpoint
+r               show the registers and flags
+x <addr> [len]  dump memory (default 64 bytes)
+dis [addr] [n]  disassemble n instructions (default: around PC)
+q               quit
+an empty line repeats the last command, addresses are hex
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Watch(Watchpoint),
+    DeleteWatch(usize),
+    Watchpoints,
+    Step(usize),
+    Next,
+    Out,
+    Continue,
+    Registers,
+    Memory(u16, usize),
+    Disassemble(Option<u16>, usize),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        let address = |index: usize| -> Result<u16, String> {
+            args.get(index)
+                .ok_or_else(|| format!("{} needs an address", name))
+                .and_then(|text| parse_address(text))
+        };
+        let count = |index: usize, default: usize| -> Result<usize, String> {
+            match args.get(index) {
+                Some(text) => text.parse().map_err(|_| format!("invalid count {}", text)),
+                None => Ok(default),
+            }
+        };
+
+        match name {
+            "b" | "break" => Ok(Command::Break(address(0)?)),
+            "d" | "delete" => Ok(Command::Delete(address(0)?)),
+            "bl" | "breakpoints" => Ok(Command::Breakpoints),
+            "w" | "watch" => parse_watchpoint(&args).map(Command::Watch),
+            "wd" => match args.first() {
+                Some(_) => Ok(Command::DeleteWatch(count(0, 0)?)),
+                None => Err("wd needs a watchpoint number".to_string()),
+            },
+            "wl" | "watchpoints" => Ok(Command::Watchpoints),
+            "s" | "step" => Ok(Command::Step(count(0, 1)?)),
+            "n" | "next" => Ok(Command::Next),
+            "out" | "finish" => Ok(Command::Out),
+            "c" | "continue" => Ok(Command::Continue),
+            "r" | "regs" => Ok(Command::Registers),
+            "x" | "mem" => Ok(Command::Memory(address(0)?, count(1, DEFAULT_DUMP_LENGTH)?)),
+            "dis" | "disasm" => match args.first() {
+                Some(_) => Ok(Command::Disassemble(
+                    Some(address(0)?),
+                    count(1, DEFAULT_DISASSEMBLY_LINES)?,
+                )),
+                None => Ok(Command::Disassemble(None, DEFAULT_DISASSEMBLY_LINES)),
+            },
+            "h" | "help" => Ok(Command::Help),
+            "q" | "quit" => Ok(Command::Quit),
+            _ => Err(format!("unknown command {}, try help", name)),
+        }
+    }
+}
+
+/// Hex with an optional `0x` or `$` prefix.
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address {}", text))
+}
+
+/// `<addr>[-<end>] [r|w|rw] [=<value>]`, values are hex like addresses.
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let range = args.first().ok_or("w needs an address")?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+        None => {
+            let address = parse_address(range)?;
+            (address, address)
+        }
+    };
+    if end < start {
+        return Err(format!("invalid range {}", range));
+    }
+
+    let mut watchpoint = Watchpoint {
+        start,
+        end,
+        access: Access::Write,
+        value: None,
+    };
+    for arg in &args[1..] {
+        match *arg {
+            "r" => watchpoint.access = Access::Read,
+            "w" => watchpoint.access = Access::Write,
+            "rw" => watchpoint.access = Access::ReadWrite,
+            _ => {
+                let value = arg
+                    .strip_prefix('=')
+                    .ok_or_else(|| format!("invalid watchpoint option {}", arg))?;
+                let value = parse_address(value)?;
+                watchpoint.value =
+                    Some(u8::try_from(value).map_err(|_| format!("invalid value {}", arg))?);
+            }
+        }
+    }
+    Ok(watchpoint)
+}
+
+/// Why a run command gave control back.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    Done,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    // PCs of the last executed instructions
+    history: VecDeque<u16>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn step(&mut self, emulator: &mut Emulator) {
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(emulator.cpu().cpu_registers.pc);
+        emulator.step_instruction();
+    }
+
+    /// Runs a CALL or RST until it returns, steps any other instruction.
+    pub fn step_over(&mut self, emulator: &mut Emulator) -> Stop {
+        let cpu = emulator.cpu();
+        let pc = cpu.cpu_registers.pc;
+        let sp = cpu.cpu_registers.sp;
+        let instruction = instruction_set().get_instruction_by_opcode(cpu.bus.bus_read(pc));
+
+        match instruction.instruction_type {
+            InstructionType::CALL | InstructionType::RST => {
+                let return_pc = pc.wrapping_add(instruction.length());
+                // the SP check keeps recursive calls from stopping early
+                self.run_until(emulator, |cpu| {
+                    cpu.cpu_registers.pc == return_pc && cpu.cpu_registers.sp >= sp
+                })
+            }
+            _ => {
+                self.step(emulator);
+                Stop::Done
+            }
+        }
+    }
+
+    /// Runs until a RET or RETI pops the return address of the current function.
+    pub fn step_out(&mut self, emulator: &mut Emulator) -> Stop {
+        let sp = emulator.cpu().cpu_registers.sp;
+        self.run_until(emulator, |cpu| {
+            matches!(
+                cpu.current_instruction.instruction_type,
+                InstructionType::RET | InstructionType::RETI
+            ) && cpu.cpu_registers.sp > sp
+        })
+    }
+
+    pub fn continue_running(&mut self, emulator: &mut Emulator) -> Stop {
+        self.run_until(emulator, |_| false)
+    }
+
+    fn run_until<F: Fn(&CpuContext) -> bool>(&mut self, emulator: &mut Emulator, done: F) -> Stop {
+        loop {
+            if let Some(stop) = self.step_and_check(emulator) {
+                return stop;
+            }
+            if done(emulator.cpu()) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// Steps once, the stop is set when the instruction hit a watchpoint or
+    /// the next one has a breakpoint.
+    fn step_and_check(&mut self, emulator: &mut Emulator) -> Option<Stop> {
+        self.step(emulator);
+        if let Some(hit) = emulator.cpu_mut().watchpoints.take_hit() {
+            return Some(Stop::Watchpoint(hit));
+        }
+        let pc = emulator.cpu().cpu_registers.pc;
+        if self.breakpoints.contains(&pc) {
+            return Some(Stop::Breakpoint(pc));
+        }
+        None
+    }
+
+    /// Runs one command and returns what it prints.
+    pub fn execute(&mut self, emulator: &mut Emulator, command: Command) -> String {
+        let mut output = String::new();
+        match command {
+            Command::Break(address) => {
+                self.add_breakpoint(address);
+                writeln!(output, "breakpoint at {:04X}", address).unwrap();
+            }
+            Command::Delete(address) => {
+                if !self.remove_breakpoint(address) {
+                    writeln!(output, "no breakpoint at {:04X}", address).unwrap();
+                }
+            }
+            Command::Breakpoints => {
+                for address in &self.breakpoints {
+                    writeln!(output, "{:04X}", address).unwrap();
+                }
+            }
+            Command::Watch(watchpoint) => {
+                emulator.cpu_mut().watchpoints.add(watchpoint);
+                writeln!(output, "watching {}", watchpoint).unwrap();
+            }
+            Command::DeleteWatch(index) => {
+                if emulator.cpu_mut().watchpoints.remove(index).is_none() {
+                    writeln!(output, "no watchpoint {}", index).unwrap();
+                }
+            }
+            Command::Watchpoints => {
+                for (index, watchpoint) in emulator.cpu().watchpoints.list().iter().enumerate() {
+                    writeln!(output, "{}: {}", index, watchpoint).unwrap();
+                }
+            }
+            Command::Step(count) => {
+                for _ in 0..count {
+                    let stop = self.step_and_check(emulator);
+                    writeln!(output, "{}", emulator.cpu()).unwrap();
+                    if let Some(stop) = stop {
+                        output.push_str(&stop_message(&stop));
+                        break;
+                    }
+                }
+                output.push_str(&self.next_instruction(emulator));
+            }
+            Command::Next | Command::Out | Command::Continue => {
+                let stop = match command {
+                    Command::Next => self.step_over(emulator),
+                    Command::Out => self.step_out(emulator),
+                    _ => self.continue_running(emulator),
+                };
+                writeln!(output, "{}", emulator.cpu()).unwrap();
+                output.push_str(&stop_message(&stop));
+                output.push_str(&self.next_instruction(emulator));
+            }
+            Command::Registers => {
+                let cpu = emulator.cpu();
+                writeln!(output, "{}", cpu.cpu_registers).unwrap();
+                writeln!(
+                    output,
+                    "SP: {:04X} PC: {:04X} IME: {} halted: {}",
+                    cpu.cpu_registers.sp,
+                    cpu.cpu_registers.pc,
+                    cpu.interrupt_master_enabled() as u8,
+                    cpu.halted() as u8
+                )
+                .unwrap();
+            }
+            Command::Memory(address, length) => {
+                output.push_str(&dump_memory(&emulator.cpu().bus, address, length))
+            }
+            Command::Disassemble(Some(address), count) => {
+                let mut address = address;
+                for _ in 0..count {
+                    let (text, length) = disassemble_at(&emulator.cpu().bus, address);
+                    writeln!(output, "  {:04X}: {}", address, text).unwrap();
+                    address = address.wrapping_add(length);
+                }
+            }
+            Command::Disassemble(None, count) => {
+                let bus = &emulator.cpu().bus;
+                for &address in &self.history {
+                    let (text, _) = disassemble_at(bus, address);
+                    writeln!(output, "  {:04X}: {}", address, text).unwrap();
+                }
+                let mut address = emulator.cpu().cpu_registers.pc;
+                for line in 0..count.saturating_sub(self.history.len()).max(1) {
+                    let (text, length) = disassemble_at(bus, address);
+                    let marker = if line == 0 { "=>" } else { "  " };
+                    writeln!(output, "{}{:04X}: {}", marker, address, text).unwrap();
+                    address = address.wrapping_add(length);
+                }
+            }
+            Command::Help => output.push_str(HELP),
+            Command::Quit => {}
+        }
+        output
+    }
+
+    fn next_instruction(&self, emulator: &Emulator) -> String {
+        let pc = emulator.cpu().cpu_registers.pc;
+        let (text, _) = disassemble_at(&emulator.cpu().bus, pc);
+        format!("=>{:04X}: {}\n", pc, text)
+    }
+
+    /// Reads commands from `input` until `q` or the end of the input.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        emulator: &mut Emulator,
+        input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        write!(output, "{}", self.next_instruction(emulator))?;
+        let mut last_line = String::new();
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(gb) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            let line = if line.trim().is_empty() {
+                last_line.clone()
+            } else {
+                line
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match Command::parse(&line) {
+                Ok(Command::Quit) => break,
+                Ok(command) => write!(output, "{}", self.execute(emulator, command))?,
+                Err(e) => writeln!(output, "{}", e)?,
+            }
+            last_line = line;
+        }
+        Ok(())
+    }
+}
+
+fn stop_message(stop: &Stop) -> String {
+    match stop {
+        Stop::Done => String::new(),
+        Stop::Breakpoint(address) => format!("breakpoint at {:04X}\n", address),
+        Stop::Watchpoint(hit) => format!("watchpoint: {}\n", hit),
+    }
+}
+
+fn dump_memory(bus: &Bus, address: u16, length: usize) -> String {
+    let mut output = String::new();
+    for row in (0..length).step_by(16) {
+        let row_address = address.wrapping_add(row as u16);
+        write!(output, "{:04X}:", row_address).unwrap();
+        for offset in 0..16.min(length - row) {
+            let byte = bus.bus_read(row_address.wrapping_add(offset as u16));
+            write!(output, " {:02X}", byte).unwrap();
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Text of the instruction at `address` and its length.
+fn disassemble_at(bus: &Bus, address: u16) -> (String, u16) {
+    let bytes: Vec<u8> = (0..3)
+        .map(|offset| bus.bus_read(address.wrapping_add(offset)))
+        .collect();
+    match disassemble_instruction(&bytes, address) {
+        Some(line) => (line.text, line.bytes.len() as u16),
+        None => unreachable!("three bytes were read"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Command, Debugger, Stop};
+    use crate::emulator::tests::test_rom;
+    use crate::emulator::Emulator;
+    use crate::watchpoint::{Access, WatchHit, Watchpoint};
+
+    /*
+     0150: call 0158
+     0153: nop
+     0154: jr 0154
+     0158: nop
+     0159: rst 38   (0038 is nop, nop, ret in the test ROM)
+     015A: ret
+    */
+    const CODE: [u8; 11] = [
+        0xCD, 0x58, 0x01, 0x00, 0x18, 0xFE, 0x00, 0x00, 0x00, 0xFF, 0xC9,
+    ];
+
+    fn debug_rom(name: &str) -> std::path::PathBuf {
+        let path = test_rom(name, &CODE);
+        let mut rom = fs::read(&path).unwrap();
+        rom[0x38..0x3B].copy_from_slice(&[0x00, 0x00, 0xC9]);
+        fs::write(&path, rom).unwrap();
+        path
+    }
+
+    fn at_entry(emulator: &mut Emulator) {
+        // nop; jp 0150
+        emulator.step_instruction();
+        emulator.step_instruction();
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("b 150"), Ok(Command::Break(0x150)));
+        assert_eq!(Command::parse("break 0x0150"), Ok(Command::Break(0x150)));
+        assert_eq!(Command::parse("s"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("s 10"), Ok(Command::Step(10)));
+        assert_eq!(
+            Command::parse("x $C000 16"),
+            Ok(Command::Memory(0xC000, 16))
+        );
+        assert_eq!(Command::parse("dis"), Ok(Command::Disassemble(None, 10)));
+        assert!(Command::parse("b").is_err());
+        assert!(Command::parse("b zz").is_err());
+        assert!(Command::parse("jump").is_err());
+        assert_eq!(
+            Command::parse("w c000-c0ff rw =3f"),
+            Ok(Command::Watch(Watchpoint {
+                start: 0xC000,
+                end: 0xC0FF,
+                access: Access::ReadWrite,
+                value: Some(0x3F),
+            }))
+        );
+        assert!(Command::parse("w c0ff-c000").is_err());
+        assert!(Command::parse("w c000 =100").is_err());
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let path = debug_rom("debugger_step_over");
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut debugger = Debugger::new();
+        at_entry(&mut emulator);
+
+        // over the call
+        assert_eq!(debugger.step_over(&mut emulator), Stop::Done);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x153);
+        assert_eq!(emulator.cpu().cpu_registers.sp, 0xFFFE);
+
+        // into the call, over the rst, then out
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        at_entry(&mut emulator);
+        debugger.step(&mut emulator);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x158);
+        debugger.step(&mut emulator);
+        assert_eq!(debugger.step_over(&mut emulator), Stop::Done);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x15A);
+        assert_eq!(debugger.step_out(&mut emulator), Stop::Done);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x153);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn breakpoints_stop_runs() {
+        let path = debug_rom("debugger_breakpoints");
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut debugger = Debugger::new();
+        at_entry(&mut emulator);
+
+        debugger.add_breakpoint(0x39);
+        assert_eq!(debugger.step_over(&mut emulator), Stop::Breakpoint(0x39));
+        assert_eq!(debugger.step_out(&mut emulator), Stop::Done);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x15A);
+
+        assert!(debugger.remove_breakpoint(0x39));
+        debugger.add_breakpoint(0x154);
+        assert_eq!(
+            debugger.continue_running(&mut emulator),
+            Stop::Breakpoint(0x154)
+        );
+        // jr to itself hits it again
+        assert_eq!(
+            debugger.continue_running(&mut emulator),
+            Stop::Breakpoint(0x154)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn repl_session() {
+        let path = debug_rom("debugger_repl");
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut debugger = Debugger::new();
+        at_entry(&mut emulator);
+
+        let input = "b 15a\nc\nr\nx 150 4\ndis\ns\n\nq\n";
+        let mut output = Vec::new();
+        debugger
+            .run(&mut emulator, input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("=>0150: CALL $0158\n"));
+        assert!(output.contains("breakpoint at 015A\n=>015A: RET\n"));
+        assert!(output.contains("SP: FFFC PC: 015A IME: 0 halted: 0\n"));
+        assert!(output.contains("0150: CD 58 01 00\n"));
+        assert!(output.contains("  003A: RET\n=>015A: RET\n  015B: NOP\n"));
+        // the empty line repeated the step
+        assert!(output.contains("=>0154: JR $0154\n(gb) "));
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x154);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watchpoints_report_the_access() {
+        // ld a,$42; ld ($D000),a; ld a,($D000); jr -2
+        let code = [0x3E, 0x42, 0xEA, 0x00, 0xD0, 0xFA, 0x00, 0xD0, 0x18, 0xFE];
+        let path = test_rom("debugger_watchpoints", &code);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut debugger = Debugger::new();
+        at_entry(&mut emulator);
+
+        let watchpoints = &mut emulator.cpu_mut().watchpoints;
+        watchpoints.add(Watchpoint {
+            start: 0xD000,
+            end: 0xD000,
+            access: Access::Write,
+            value: None,
+        });
+        watchpoints.add(Watchpoint {
+            start: 0xCFFF,
+            end: 0xD001,
+            access: Access::Read,
+            value: Some(0x42),
+        });
+
+        let hit = WatchHit {
+            pc: 0x152,
+            address: 0xD000,
+            access: Access::Write,
+            old_value: 0x00,
+            new_value: 0x42,
+            bank: Some(1),
+        };
+        assert_eq!(
+            debugger.continue_running(&mut emulator),
+            Stop::Watchpoint(hit)
+        );
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x155);
+        assert_eq!(
+            format!("{}", hit),
+            "write of D000 at PC 0152: 00 -> 42 (bank 1)"
+        );
+
+        let output = debugger.execute(&mut emulator, Command::Continue);
+        assert!(output.contains("watchpoint: read of D000 at PC 0155: 42 -> 42 (bank 1)\n"));
+
+        emulator.cpu_mut().watchpoints.remove(1);
+        debugger.add_breakpoint(0x158);
+        assert_eq!(
+            debugger.continue_running(&mut emulator),
+            Stop::Breakpoint(0x158)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::fmt::Result as FmtResult;
+
+use crate::cartridge::cart::ROM_BANK_SIZE;
+use crate::cpu::instruction::{ConditionType, Instruction, InstructionType, Operand};
+use crate::cpu::instruction_set::instruction_set;
+
+// bytes per DB line
+const DATA_LINE_LENGTH: usize = 8;
+
+const CB_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const CB_SHIFTS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// where the CPU starts and the RST and interrupt vectors
+const BANK_0_ENTRIES: [u16; 14] = [
+    0x0100, 0x0000, 0x0008, 0x0010, 0x0018, 0x0020, 0x0028, 0x0030, 0x0038, 0x0040, 0x0048, 0x0050,
+    0x0058, 0x0060,
+];
+
+pub struct DisassembledLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Display for DisassembledLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> FmtResult {
+        let bytes: Vec<String> = self
+            .bytes
+            .iter()
+            .take(3)
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        write!(
+            f,
+            "{:04X}: {:8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which sits at `address`.
+/// An opcode with missing operand bytes or an unused opcode comes out as a
+/// single DB byte. None when `bytes` is empty.
+pub fn disassemble_instruction(bytes: &[u8], address: u16) -> Option<DisassembledLine> {
+    let opcode = *bytes.first()?;
+    let instruction = instruction_set().get_instruction_by_opcode(opcode);
+    let length = instruction.length() as usize;
+    if instruction.instruction_type == InstructionType::NONE || bytes.len() < length {
+        return Some(data_line(&bytes[..1], address));
+    }
+
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let description = instruction.description;
+    let text = if opcode == 0xCB {
+        let register = CB_REGISTERS[(byte & 0x07) as usize];
+        let bit = (byte >> 3) & 0x07;
+        match byte >> 6 {
+            0 => format!("{} {}", CB_SHIFTS[bit as usize], register),
+            1 => format!("BIT {},{}", bit, register),
+            2 => format!("RES {},{}", bit, register),
+            _ => format!("SET {},{}", bit, register),
+        }
+    } else if description.contains("16") {
+        let value = format!("${:04X}", word);
+        description.replace("d16", &value).replace("a16", &value)
+    } else if instruction.instruction_type == InstructionType::JR {
+        description.replace("r8", &format!("${:04X}", relative_target(address, byte)))
+    } else if description.contains("r8") {
+        let offset = byte as i8;
+        let sign = if offset < 0 { "-" } else { "+" };
+        let value = format!("{}${:02X}", sign, offset.unsigned_abs());
+        description.replace("+r8", "r8").replace("r8", &value)
+    } else {
+        description
+            .replace("d8", &format!("${:02X}", byte))
+            .replace("a8", &format!("$FF{:02X}", byte))
+    };
+
+    Some(DisassembledLine {
+        address,
+        bytes: bytes[..length].to_vec(),
+        text,
+    })
+}
+
+/// Decodes `bytes` loaded at `address` from start to end, treating every byte
+/// as code.
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while let Some(line) =
+        disassemble_instruction(&bytes[offset..], address.wrapping_add(offset as u16))
+    {
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Decodes the code reachable from `entries` by following jumps, calls and
+/// RSTs, the bytes nothing reaches come out as DB lines. Targets outside of
+/// `bytes` (another bank, RAM) are not followed.
+pub fn disassemble_reachable(bytes: &[u8], address: u16, entries: &[u16]) -> Vec<DisassembledLine> {
+    let code_starts = find_code(bytes, address, entries);
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let line_address = address.wrapping_add(offset as u16);
+        let instruction = if code_starts.contains(&offset) {
+            disassemble_instruction(&bytes[offset..], line_address)
+        } else {
+            None
+        };
+        if let Some(line) = instruction {
+            offset += line.bytes.len();
+            lines.push(line);
+        } else {
+            let mut end = offset + 1;
+            while end < bytes.len()
+                && end - offset < DATA_LINE_LENGTH
+                && !code_starts.contains(&end)
+            {
+                end += 1;
+            }
+            lines.push(data_line(&bytes[offset..end], line_address));
+            offset = end;
+        }
+    }
+    lines
+}
+
+/// Offsets of the instructions reached from `entries`.
+fn find_code(bytes: &[u8], address: u16, entries: &[u16]) -> BTreeSet<usize> {
+    let offset_of = |target: u16| {
+        let offset = target.wrapping_sub(address) as usize;
+        (target >= address && offset < bytes.len()).then_some(offset)
+    };
+
+    let mut covered = vec![false; bytes.len()];
+    let mut code_starts = BTreeSet::new();
+    let mut pending: Vec<usize> = entries
+        .iter()
+        .filter_map(|&entry| offset_of(entry))
+        .collect();
+
+    while let Some(mut offset) = pending.pop() {
+        // runs along the instructions until one doesn't fall through
+        while offset < bytes.len() && !covered[offset] {
+            let instruction = instruction_set().get_instruction_by_opcode(bytes[offset]);
+            let length = instruction.length() as usize;
+            if instruction.instruction_type == InstructionType::NONE
+                || offset + length > bytes.len()
+            {
+                break;
+            }
+            covered[offset..offset + length].fill(true);
+            code_starts.insert(offset);
+
+            let instruction_address = address.wrapping_add(offset as u16);
+            let (target, falls_through) =
+                branch(instruction, &bytes[offset..], instruction_address);
+            if let Some(target) = target.and_then(offset_of) {
+                pending.push(target);
+            }
+            if !falls_through {
+                break;
+            }
+            offset += length;
+        }
+    }
+    code_starts
+}
+
+/// Where the instruction can jump to, and whether the next instruction can
+/// run after it.
+fn branch(instruction: &Instruction, bytes: &[u8], address: u16) -> (Option<u16>, bool) {
+    let conditional = instruction.condition != ConditionType::None;
+    match instruction.instruction_type {
+        InstructionType::JP => match instruction.operand_1 {
+            Operand::A16 => (Some(u16::from_le_bytes([bytes[1], bytes[2]])), conditional),
+            // JP HL, the target is only known at run time
+            _ => (None, false),
+        },
+        InstructionType::JR => (Some(relative_target(address, bytes[1])), conditional),
+        InstructionType::CALL => (Some(u16::from_le_bytes([bytes[1], bytes[2]])), true),
+        InstructionType::RST => (instruction.parameter.map(|vector| vector as u16), true),
+        InstructionType::RET => (None, conditional),
+        InstructionType::RETI | InstructionType::JPHL => (None, false),
+        _ => (None, true),
+    }
+}
+
+fn relative_target(address: u16, offset: u8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+fn data_line(bytes: &[u8], address: u16) -> DisassembledLine {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+    DisassembledLine {
+        address,
+        bytes: bytes.to_vec(),
+        text: format!("DB {}", values.join(",")),
+    }
+}
+
+/// Disassembles a 16 KiB ROM bank at the address it is mapped to, bank 0 at
+/// 0000 and the others at 4000. The code is followed from `from`, or from the
+/// start address and the RST and interrupt vectors for bank 0; the listing
+/// starts at `from` as well.
+pub fn disassemble_bank(
+    rom: &[u8],
+    bank: usize,
+    from: Option<u16>,
+) -> Result<Vec<DisassembledLine>, String> {
+    let start = bank * ROM_BANK_SIZE;
+    if start >= rom.len() {
+        return Err(format!(
+            "bank {} is out of the ROM, it has {} banks",
+            bank,
+            rom.len().div_ceil(ROM_BANK_SIZE)
+        ));
+    }
+    let bytes = &rom[start..(start + ROM_BANK_SIZE).min(rom.len())];
+    let address: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+    let end = address as usize + bytes.len();
+
+    let entries = match from {
+        Some(from) if (address as usize..end).contains(&(from as usize)) => vec![from],
+        Some(from) => {
+            return Err(format!(
+                "{:04X} is not in bank {} ({:04X}-{:04X})",
+                from,
+                bank,
+                address,
+                end - 1
+            ))
+        }
+        None if bank == 0 => BANK_0_ENTRIES.to_vec(),
+        None => vec![address],
+    };
+
+    let first = from.unwrap_or(address);
+    Ok(disassemble_reachable(bytes, address, &entries)
+        .into_iter()
+        .filter(|line| line.address >= first)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, disassemble_bank, disassemble_instruction, disassemble_reachable};
+
+    fn texts(bytes: &[u8], address: u16) -> Vec<String> {
+        disassemble(bytes, address)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn operands_and_cb_opcodes() {
+        let code = [
+            0x01, 0x34, 0x12, // ld bc,$1234
+            0xE0, 0x44, // ldh ($FF44),a
+            0x18, 0xFE, // jr to itself
+            0xF8, 0xFE, // ld hl,sp-2
+            0xCB, 0x7C, // bit 7,h
+            0xCB, 0x37, // swap a
+            0xCB, 0xC6, // set 0,(hl)
+            0x9A, // sbc a,d
+            0xD3, // unused
+            0xC3, 0x50, // jp with its last byte missing, 50 is ld d,b
+        ];
+        assert_eq!(
+            texts(&code, 0x150),
+            [
+                "LD BC,$1234",
+                "LDH ($FF44),A",
+                "JR $0155",
+                "LD HL,SP-$02",
+                "BIT 7,H",
+                "SWAP A",
+                "SET 0,(HL)",
+                "SBC A,D",
+                "DB $D3",
+                "DB $C3",
+                "LD D,B",
+            ]
+        );
+
+        let line = disassemble_instruction(&[0xCD, 0x00, 0x40], 0x0150).unwrap();
+        assert_eq!(format!("{}", line), "0150: CD 00 40  CALL $4000");
+        assert!(disassemble_instruction(&[], 0x0150).is_none());
+    }
+
+    #[test]
+    fn recursive_descent_skips_data() {
+        let code = [
+            0xCD, 0x08, 0x01, // 0100: call 0108
+            0x20, 0x01, // 0103: jr nz,0106
+            0xC9, // 0105: ret
+            0x18, 0xFE, // 0106: jr to itself
+            0xC9, // 0108: ret
+            0x12, 0x34, 0x56, // 0109: data
+            0xC3, 0x00, 0x80, // 010C: jp 8000, not in the buffer
+        ];
+        let lines: Vec<String> = disassemble_reachable(&code, 0x100, &[0x100])
+            .iter()
+            .map(|line| format!("{:04X} {}", line.address, line.text))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "0100 CALL $0108",
+                "0103 JR NZ,$0106",
+                "0105 RET",
+                "0106 JR $0106",
+                "0108 RET",
+                "0109 DB $12,$34,$56,$C3,$00,$80",
+            ]
+        );
+    }
+
+    #[test]
+    fn banks_and_start_address() {
+        let mut rom = vec![0xFF; 0x8000];
+        // 4000: ld a,1; ret
+        rom[0x4000..0x4003].copy_from_slice(&[0x3E, 0x01, 0xC9]);
+
+        let lines = disassemble_bank(&rom, 1, Some(0x4000)).unwrap();
+        assert_eq!(lines[0].text, "LD A,$01");
+        assert_eq!(lines[1].address, 0x4002);
+        assert_eq!(lines[2].text, "DB $FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF");
+
+        // nop; jp 0150 and jr to itself there
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x152].copy_from_slice(&[0x18, 0xFE]);
+        let lines = disassemble_bank(&rom, 0, Some(0x100)).unwrap();
+        assert_eq!(lines[1].text, "JP $0150");
+        assert_eq!(lines[2].text, "DB $FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF");
+        // without a start address bank 0 is followed from 0100 and the vectors
+        let lines = disassemble_bank(&rom, 0, None).unwrap();
+        assert_eq!(lines[0].text, "RST 38H");
+        assert!(lines
+            .iter()
+            .any(|line| line.address == 0x150 && line.text == "JR $0150"));
+
+        assert!(disassemble_bank(&rom, 2, None).is_err());
+        assert!(disassemble_bank(&rom, 1, Some(0x150)).is_err());
+    }
+}
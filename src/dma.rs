@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct DMA {
     pub active: bool,
@@ -27,3 +29,150 @@ impl DMA {
         self.active
     }
 }
+
+impl SaveState for DMA {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.active);
+        writer.write_u8(self.byte);
+        writer.write_u8(self.value);
+        writer.write_u8(self.start_delay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.active = reader.read_bool()?;
+        self.byte = reader.read_u8()?;
+        self.value = reader.read_u8()?;
+        self.start_delay = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/*
+ CGB VRAM DMA
+ FF51 HDMA1  source high
+ FF52 HDMA2  source low, bits 3-0 ignored
+ FF53 HDMA3  destination high, bits 7-5 ignored (always in VRAM)
+ FF54 HDMA4  destination low, bits 3-0 ignored
+ FF55 HDMA5  write: Bit7 mode (0=general purpose, 1=H-blank)
+                    Bit6-0 length / 0x10 - 1
+             read:  Bit7 0=H-blank transfer active
+                    Bit6-0 blocks left - 1 (0x7F once done)
+
+ The CPU is stopped while a block is copied, 32 dots per 0x10 bytes in both
+ speeds. A general purpose transfer copies every block at once, a H-blank
+ transfer one block at the start of each HBlank until it's done or HDMA5 is
+ written with bit 7 cleared.
+*/
+#[derive(Debug)]
+pub struct HDMA {
+    source: u16,
+    destination: u16,
+    length: u8,
+    pub hblank: bool,
+    pub general: bool,
+    pub in_hblank: bool,
+    // a H-blank transfer reached HBlank, the next block waits for the CPU
+    pub block_due: bool,
+}
+
+impl HDMA {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            hblank: false,
+            general: false,
+            in_hblank: false,
+            block_due: false,
+        }
+    }
+
+    pub fn hdma_read(&self, address: u16) -> u8 {
+        match address {
+            0xFF55 => ((!self.hblank as u8) << 7) | self.length,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn hdma_write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => {
+                self.destination = (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8)
+            }
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                if self.hblank && value & 0x80 == 0 {
+                    // stops the H-blank transfer, the length stays readable
+                    self.hblank = false;
+                    self.block_due = false;
+                    return;
+                }
+                self.length = value & 0x7F;
+                self.hblank = value & 0x80 != 0;
+                self.general = !self.hblank;
+            }
+            _ => {}
+        }
+    }
+
+    /// Source and VRAM destination of the next 0x10 byte block, the transfer
+    /// ends after the last one.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.destination);
+        self.block_due = false;
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+
+        if self.length == 0 {
+            self.length = 0x7F;
+            self.hblank = false;
+            self.general = false;
+        } else {
+            self.length -= 1;
+        }
+        block
+    }
+
+    pub fn hdma_is_transferring(&self) -> bool {
+        self.hblank || self.general
+    }
+
+    /// A block must be copied before the CPU goes on.
+    pub fn block_pending(&self) -> bool {
+        self.general || self.block_due
+    }
+}
+
+impl Default for HDMA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for HDMA {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.length);
+        writer.write_bool(self.hblank);
+        writer.write_bool(self.general);
+        writer.write_bool(self.in_hblank);
+        writer.write_bool(self.block_due);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.source = reader.read_u16()?;
+        self.destination = reader.read_u16()?;
+        self.length = reader.read_u8()?;
+        self.hblank = reader.read_bool()?;
+        self.general = reader.read_bool()?;
+        self.in_hblank = reader.read_bool()?;
+        if reader.version >= 7 {
+            self.block_due = reader.read_bool()?;
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,29 @@
-use core::time;
+#[cfg(feature = "window")]
+use std::thread;
 use std::{
-    sync::mpsc,
-    thread,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
     time::{Duration, Instant},
 };
 
 use crate::{
-    cpu::{util::ValueEnum, CpuContext},
-    ui::UI,
+    apu::APU_SAMPLE_RATE,
+    audio::{Resampler, WavWriter},
+    emulator::{Emulator, CPU_CLOCK_HZ, CYCLES_PER_FRAME},
+};
+#[cfg(feature = "window")]
+use crate::{
+    audio::{open_live_sink, LIVE_SAMPLE_RATE},
+    ppu::{LCD_HEIGHT, LCD_WIDTH},
+    ui::{FAST_FORWARD_KEY, FRAME_ADVANCE_KEY, PAUSE_KEY, UI},
 };
 
-use minifb::{Key, Scale};
+#[cfg(feature = "window")]
+use minifb::{Key, KeyRepeat, Scale};
+
+// LY value gameboy-doctor logs are made with
+pub const DOCTOR_LY: u8 = 0x90;
 
 pub struct EmuContext;
 
@@ -19,69 +32,232 @@ pub struct SharedData {
     pub tile: [u8; 16],
 }
 
+// after a hiccup longer than this the pacer starts over instead of running
+// frames back to back to catch up
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+/// Spaces frames 70224 T-cycles apart in real time (about 59.73 Hz).
+pub struct FramePacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            frame_duration: Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CPU_CLOCK_HZ as f64),
+            next_frame: now,
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    /// Starts the schedule over, after a pause or fast-forward.
+    pub fn reset(&mut self, now: Instant) {
+        self.next_frame = now;
+    }
+
+    /// Returns how long to wait before running the next frame.
+    pub fn frame_done(&mut self, now: Instant) -> Duration {
+        self.next_frame += self.frame_duration;
+        if self.next_frame > now {
+            return self.next_frame - now;
+        }
+        if now - self.next_frame > self.frame_duration * MAX_FRAMES_BEHIND {
+            self.next_frame = now;
+        }
+        Duration::ZERO
+    }
+}
+
+#[cfg(feature = "window")]
 impl EmuContext {
-    pub fn run(&mut self, cpu: &mut CpuContext) {
-        let mut ui = UI::new(16 * 8, 24 * 8, Scale::X4);
+    /// Windowed main loop, one frame per 1/59.73 s. P pauses, N advances one
+    /// frame while paused and holding Tab runs as fast as possible.
+    pub fn run(&mut self, emulator: &mut Emulator) {
+        let mut ui = UI::new(LCD_WIDTH, LCD_HEIGHT, Scale::X4);
+        // the frames are paced here, not by minifb
+        ui.dbg_window.limit_update_rate(None);
+
+        let mut audio = open_live_sink(LIVE_SAMPLE_RATE)
+            .map(|sink| (Resampler::new(APU_SAMPLE_RATE, sink.sample_rate()), sink));
+
+        let mut frame = emulator.framebuffer().to_vec();
+        let mut frame_end = emulator.cycles();
+        let mut pacer = FramePacer::new(Instant::now());
+        let mut paused = false;
+        let mut title = String::new();
 
-        let mut stat_cpu = Stats::new();
-        let mut stat_ui = Stats::new();
-        let mut now;
-        let mut elapsed;
         while ui.dbg_window.is_open() && !ui.dbg_window.is_key_down(Key::Escape) {
-            now = Instant::now();
-            cpu.cpu_step();
-            elapsed = now.elapsed().subsec_nanos();
-            stat_cpu.put(elapsed);
-
-            if stat_cpu.count > 100_000 {
-                dbg!(stat_cpu);
-                dbg!(stat_ui);
-                return;
+            if ui.dbg_window.is_key_pressed(PAUSE_KEY, KeyRepeat::No) {
+                paused = !paused;
+                pacer.reset(Instant::now());
             }
-            now = Instant::now();
-            if let Some(address) = cpu.last_written_address {
-                if address > 0x8000 && address < 0xA000 {
-                    ui.update(cpu);
+            let fast_forward = !paused && ui.dbg_window.is_key_down(FAST_FORWARD_KEY);
+            let frame_advance = paused
+                && ui
+                    .dbg_window
+                    .is_key_pressed(FRAME_ADVANCE_KEY, KeyRepeat::Yes);
+
+            if !paused || frame_advance {
+                frame_end += CYCLES_PER_FRAME;
+                run_frame(emulator, frame_end, &mut frame);
+
+                let samples = emulator.take_audio_samples();
+                if let Some((resampler, sink)) = audio.as_mut() {
+                    // sped up or single frames would only be noise
+                    if !paused && !fast_forward {
+                        sink.push_samples(&resampler.resample(&samples));
+                    }
                 }
             }
-            elapsed = now.elapsed().subsec_nanos();
-            stat_ui.put(elapsed);
+
+            ui.update_frame(&frame);
+            ui.update_joypad(emulator);
+
+            let new_title = match (paused, fast_forward) {
+                (true, _) => "rusty_gb - paused (P: resume, N: next frame)",
+                (_, true) => "rusty_gb - fast forward",
+                _ => "rusty_gb",
+            };
+            if title != new_title {
+                title = new_title.to_string();
+                ui.dbg_window.set_title(&title);
+            }
+
+            if fast_forward {
+                pacer.reset(Instant::now());
+            } else {
+                thread::sleep(pacer.frame_done(Instant::now()));
+            }
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Stats {
-    min: u32,
-    max: u32,
-    average: u32,
-    count: u32,
-    sum: u32,
+/// Runs the emulator up to `frame_end` T-cycles, `frame` gets the picture
+/// of the last VBlank so a frame is never shown half drawn.
+#[cfg(feature = "window")]
+fn run_frame(emulator: &mut Emulator, frame_end: usize, frame: &mut [u32]) {
+    while emulator.cycles() < frame_end {
+        emulator.step_instruction();
+        let ppu = &mut emulator.cpu_mut().bus.ppu;
+        if ppu.frame_ready {
+            ppu.frame_ready = false;
+            frame.copy_from_slice(&ppu.framebuffer);
+        }
+    }
 }
 
-impl Stats {
-    pub fn new() -> Self {
-        Self {
-            min: 1_000_000_000,
-            max: 0,
-            average: 0,
-            count: 0,
-            sum: 0,
+impl EmuContext {
+    /// Runs `frames` frames without a window and writes the sound to a WAV
+    /// file at `sample_rate`.
+    pub fn record_audio<P: AsRef<Path>>(
+        &mut self,
+        emulator: &mut Emulator,
+        path: P,
+        sample_rate: u32,
+        frames: usize,
+    ) -> io::Result<()> {
+        let mut wav = WavWriter::create(path, sample_rate)?;
+        let mut resampler = Resampler::new(APU_SAMPLE_RATE, sample_rate);
+
+        let start = emulator.cycles();
+        for frame in 1..=frames {
+            let end = start + frame * CYCLES_PER_FRAME;
+            emulator.run_cycles(end.saturating_sub(emulator.cycles()));
+            wav.write_samples(&resampler.resample(&emulator.take_audio_samples()))?;
         }
+        wav.finish()
     }
-    pub fn put(&mut self, elapsed: u32) {
-        self.count += 1;
-        self.min = if self.min < elapsed {
-            self.min
-        } else {
-            elapsed
-        };
-        self.max = if self.max < elapsed {
-            elapsed
-        } else {
-            self.max
-        };
-        self.sum += elapsed;
-        self.average = self.sum / self.count;
+
+    /// Runs `frames` frames without a window and logs the registers before
+    /// every instruction in the gameboy-doctor format, with LY stubbed like
+    /// the tool expects.
+    pub fn trace<P: AsRef<Path>>(
+        &mut self,
+        emulator: &mut Emulator,
+        path: P,
+        frames: usize,
+    ) -> io::Result<()> {
+        let mut log = BufWriter::new(File::create(path)?);
+        emulator.cpu_mut().bus.stub_ly(DOCTOR_LY);
+
+        let end = emulator.cycles() + frames * CYCLES_PER_FRAME;
+        while emulator.cycles() < end {
+            // halted M-cycles are not instructions
+            if !emulator.cpu().halted() {
+                writeln!(log, "{}", emulator.cpu().doctor_trace())?;
+            }
+            emulator.step_instruction();
+        }
+        log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use std::fs;
+
+    use super::{EmuContext, FramePacer};
+    use crate::emulator::tests::test_rom;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn frame_pacer_waits_for_the_next_frame() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let frame = pacer.frame_duration();
+        assert_eq!(frame.as_micros(), 16742);
+
+        // the frame took 5 ms to emulate
+        let wait = pacer.frame_done(start + Duration::from_millis(5));
+        assert_eq!(wait, frame - Duration::from_millis(5));
+
+        // a slow frame is made up by the next one
+        let wait = pacer.frame_done(start + frame * 2 + Duration::from_millis(1));
+        assert_eq!(wait, Duration::ZERO);
+        let wait = pacer.frame_done(start + frame * 2 + Duration::from_millis(2));
+        assert_eq!(wait, frame - Duration::from_millis(2));
+    }
+
+    #[test]
+    fn frame_pacer_gives_up_catching_up() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let frame = pacer.frame_duration();
+
+        // a one second hiccup doesn't make the next frames run back to back
+        let late = start + Duration::from_secs(1);
+        assert_eq!(pacer.frame_done(late), Duration::ZERO);
+        assert_eq!(pacer.frame_done(late), frame);
+    }
+
+    #[test]
+    fn doctor_trace_log() {
+        // ldh a,($44); jr -2
+        let path = test_rom("doctor_trace_log", &[0xF0, 0x44, 0x18, 0xFE]);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let log_path = std::env::temp_dir().join("rusty_gb_doctor_trace_log.txt");
+        EmuContext.trace(&mut emulator, &log_path, 1).unwrap();
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().take(5).collect();
+        assert_eq!(
+            lines,
+            [
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:F0,44,18,FE",
+                // LY is stubbed
+                "A:90 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:18,FE,00,00",
+                "A:90 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:18,FE,00,00",
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&log_path).unwrap();
     }
 }
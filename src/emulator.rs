@@ -0,0 +1,399 @@
+use std::path::Path;
+
+use crate::{
+    bus::Bus,
+    cartridge::Cartridge,
+    cpu::{instruction_set::instruction_set, CpuContext},
+    io::Button,
+    model::Model,
+    ppu::PpuRenderer,
+    savestate::SaveStateError,
+};
+
+pub const CPU_CLOCK_HZ: usize = 4_194_304;
+
+/// T-cycles from one VBlank to the next, 154 lines of 456 dots.
+pub const CYCLES_PER_FRAME: usize = 70224;
+
+/// The whole machine without any frontend, windowed and headless runners are
+/// built on top of it.
+pub struct Emulator<'a> {
+    cpu: CpuContext<'a>,
+}
+
+impl<'a> Emulator<'a> {
+    pub fn new(rom_file: &'a str) -> Self {
+        Emulator::with_bus(Bus::new(rom_file))
+    }
+
+    pub fn with_model(rom_file: &'a str, model: Model) -> Self {
+        Emulator::with_bus(Bus::with_model(Cartridge::load(rom_file), model))
+    }
+
+    /// Starts with `boot_rom` mapped at 0x0000 instead of the post-boot state.
+    pub fn with_boot_rom(mut bus: Bus<'a>, boot_rom: Vec<u8>) -> Result<Self, String> {
+        bus.load_boot_rom(boot_rom)?;
+        Ok(Emulator::with_bus(bus))
+    }
+
+    pub fn with_bus(bus: Bus<'a>) -> Self {
+        Self {
+            cpu: CpuContext::with_bus(bus, instruction_set()),
+        }
+    }
+
+    pub fn cpu(&self) -> &CpuContext<'a> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CpuContext<'a> {
+        &mut self.cpu
+    }
+
+    /// Picks how the PPU draws mode 3, see `PpuRenderer`.
+    pub fn set_renderer(&mut self, renderer: PpuRenderer) {
+        self.cpu.bus.ppu.set_renderer(renderer);
+    }
+
+    /// T-cycles run since power on.
+    pub fn cycles(&self) -> usize {
+        self.cpu.ticks
+    }
+
+    /// Runs one instruction (or one M-cycle while halted), returns the
+    /// T-cycles it took.
+    pub fn step_instruction(&mut self) -> usize {
+        let start = self.cpu.ticks;
+        self.cpu.cpu_step();
+        self.cpu.ticks - start
+    }
+
+    /// Runs whole instructions until at least `cycles` T-cycles went by,
+    /// returns the T-cycles actually run.
+    pub fn run_cycles(&mut self, cycles: usize) -> usize {
+        let start = self.cpu.ticks;
+        while self.cpu.ticks - start < cycles {
+            self.cpu.cpu_step();
+        }
+        self.cpu.ticks - start
+    }
+
+    /// Runs until the PPU finished a frame, or for one frame worth of cycles
+    /// while the LCD is off. Returns the T-cycles run.
+    pub fn run_frame(&mut self) -> usize {
+        let start = self.cpu.ticks;
+        while self.cpu.ticks - start < CYCLES_PER_FRAME {
+            self.cpu.cpu_step();
+            if self.cpu.bus.ppu.frame_ready {
+                self.cpu.bus.ppu.frame_ready = false;
+                break;
+            }
+        }
+        self.cpu.ticks - start
+    }
+
+    /// 160x144 pixels as 0RGB.
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.cpu.bus.ppu.framebuffer
+    }
+
+    /// Interleaved stereo samples at APU_SAMPLE_RATE since the last call.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.cpu.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.cpu.release(button);
+    }
+
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        self.cpu.save_snapshot()
+    }
+
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        self.cpu.load_snapshot(data)
+    }
+
+    pub fn save_snapshot_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        self.cpu.save_snapshot_file(path)
+    }
+
+    pub fn load_snapshot_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        self.cpu.load_snapshot_file(path)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::{Emulator, CYCLES_PER_FRAME};
+    use crate::bus::Bus;
+    use crate::cartridge::cart::Cartridge;
+    use crate::model::Model;
+    use crate::ppu::{LCD_HEIGHT, LCD_WIDTH};
+
+    /// Writes a 32 KiB ROM running `code` from 0x150 to the temp directory.
+    pub fn test_rom(name: &str, code: &[u8]) -> PathBuf {
+        write_test_rom(name, code, 0x00)
+    }
+
+    /// Same as `test_rom` with the header flagging a CGB game.
+    pub fn cgb_test_rom(name: &str, code: &[u8]) -> PathBuf {
+        write_test_rom(name, code, 0x80)
+    }
+
+    fn write_test_rom(name: &str, code: &[u8], cgb_flag: u8) -> PathBuf {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = cgb_flag;
+        // nop; jp 0x150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x150 + code.len()].copy_from_slice(code);
+        let path = std::env::temp_dir().join(format!("rusty_gb_{}.gb", name));
+        fs::write(&path, rom).unwrap();
+        path
+    }
+
+    #[test]
+    fn steps_and_runs_cycles() {
+        // nop; jr -2
+        let path = test_rom("steps_and_runs_cycles", &[0x00, 0x18, 0xFD]);
+        let rom_file = path.to_str().unwrap();
+        let mut emulator = Emulator::new(rom_file);
+
+        // nop at 0x100
+        assert_eq!(emulator.step_instruction(), 4);
+        // jp 0x150
+        assert_eq!(emulator.step_instruction(), 16);
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x150);
+
+        let cycles = emulator.run_cycles(1000);
+        assert!((1000..1012).contains(&cycles));
+        assert_eq!(emulator.cycles(), 20 + cycles);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn runs_whole_frames() {
+        let path = test_rom("runs_whole_frames", &[0x18, 0xFE]);
+        let rom_file = path.to_str().unwrap();
+        let mut emulator = Emulator::new(rom_file);
+        assert_eq!(emulator.framebuffer().len(), LCD_WIDTH * LCD_HEIGHT);
+
+        // the first frame ends at the first VBlank
+        emulator.run_frame();
+        for _ in 0..3 {
+            let cycles = emulator.run_frame();
+            assert!(cycles.abs_diff(CYCLES_PER_FRAME) < 12);
+        }
+
+        // LCD off: frames are timed by cycles only
+        let _ = emulator.cpu_mut().bus.ppu.lcd_write(0xFF40, 0x00);
+        let cycles = emulator.run_frame();
+        assert!((CYCLES_PER_FRAME..CYCLES_PER_FRAME + 12).contains(&cycles));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cgb_wram_banks_and_speed_switch() {
+        // ld a, 1; ldh (0x4D), a; stop; jr -2
+        let code = [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x18, 0xFE];
+        let path = cgb_test_rom("cgb_wram_banks_and_speed_switch", &code);
+        let rom_file = path.to_str().unwrap();
+        let mut emulator = Emulator::new(rom_file);
+        let bus = &mut emulator.cpu_mut().bus;
+        assert!(bus.cgb_mode());
+
+        // SVBK 0 maps bank 1
+        bus.bus_write8(0xFF70, 2);
+        bus.bus_write8(0xD000, 0x22);
+        bus.bus_write8(0xFF70, 0);
+        bus.bus_write8(0xD000, 0x11);
+        assert_eq!(bus.bus_read(0xFF70), 0xF8);
+        bus.bus_write8(0xFF70, 2);
+        assert_eq!(bus.bus_read(0xD000), 0x22);
+        bus.bus_write8(0xFF70, 1);
+        assert_eq!(bus.bus_read(0xD000), 0x11);
+
+        // nop, jp, ld, ldh, stop
+        for _ in 0..5 {
+            emulator.step_instruction();
+        }
+        assert!(emulator.cpu().bus.double_speed());
+        assert_eq!(emulator.cpu().bus.bus_read(0xFF4D), 0xFE);
+        // jr takes 12 CPU cycles, 6 dots in double speed
+        assert_eq!(emulator.step_instruction(), 6);
+
+        // a VRAM DMA block takes as long as in normal speed
+        emulator.cpu_mut().bus.bus_write8(0xFF55, 0x00);
+        assert_eq!(emulator.step_instruction(), 32 + 6);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn general_and_hblank_vram_dma() {
+        // ld a, 0x01; ldh (0x55), a; nop; ld a, 0x81; ldh (0x55), a; jr -2
+        let code = [
+            0x3E, 0x01, 0xE0, 0x55, 0x00, 0x3E, 0x81, 0xE0, 0x55, 0x18, 0xFE,
+        ];
+        let path = cgb_test_rom("general_and_hblank_vram_dma", &code);
+        let rom_file = path.to_str().unwrap();
+        let mut emulator = Emulator::new(rom_file);
+
+        let bus = &mut emulator.cpu_mut().bus;
+        for offset in 0..0x40 {
+            bus.bus_write8(0xC000 + offset, offset as u8 + 1);
+        }
+        // from C000 to 8800 in VRAM bank 1
+        bus.bus_write8(0xFF4F, 1);
+        for (address, value) in [
+            (0xFF51, 0xC0),
+            (0xFF52, 0x00),
+            (0xFF53, 0x08),
+            (0xFF54, 0x00),
+        ] {
+            bus.bus_write8(address, value);
+        }
+
+        // nop, jp, ld, ldh
+        for _ in 0..4 {
+            emulator.step_instruction();
+        }
+        // the CPU waits 8 M-cycles per block before the nop
+        assert_eq!(emulator.step_instruction(), 2 * 32 + 4);
+        let bus = &emulator.cpu().bus;
+        assert_eq!(bus.bus_read(0xFF55), 0xFF);
+        assert_eq!(bus.bus_read(0x8800), 0x01);
+        assert_eq!(bus.bus_read(0x881F), 0x20);
+
+        // H-blank transfer of 2 blocks, carrying on from C020 to 8820
+        emulator.step_instruction();
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu().bus.bus_read(0xFF55), 0x01);
+        emulator.run_cycles(456);
+        let bus = &mut emulator.cpu_mut().bus;
+        assert_eq!(bus.bus_read(0x8820), 0x21);
+        assert_eq!(bus.bus_read(0x882F), 0x30);
+        assert_eq!(bus.bus_read(0x8830), 0x00);
+        assert_eq!(bus.bus_read(0xFF55), 0x00);
+
+        // cancelled before the last block
+        bus.bus_write8(0xFF55, 0x00);
+        assert_eq!(bus.bus_read(0xFF55), 0x80);
+        emulator.run_cycles(456 * 2);
+        assert_eq!(emulator.cpu().bus.bus_read(0x8830), 0x00);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn boot_rom_runs_until_unmapped() {
+        let path = test_rom("boot_rom_runs_until_unmapped", &[0x18, 0xFE]);
+        let rom_file = path.to_str().unwrap();
+        assert!(Emulator::with_boot_rom(Bus::new(rom_file), vec![0; 0x900]).is_err());
+
+        // nops, then ld a, 1; ldh (0x50), a at the end like the real one
+        let mut boot_rom = vec![0; 0x100];
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut emulator = Emulator::with_boot_rom(Bus::new(rom_file), boot_rom).unwrap();
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x0000);
+        assert_eq!(emulator.cpu().cpu_registers.a, 0x00);
+        assert_eq!(emulator.cpu().bus.bus_read(0xFF40), 0x00);
+        assert_eq!(emulator.cpu().bus.bus_read(0x00FC), 0x3E);
+
+        while emulator.cpu().cpu_registers.pc != 0x100 {
+            emulator.step_instruction();
+        }
+        let bus = &mut emulator.cpu_mut().bus;
+        assert!(!bus.boot_rom_mapped());
+        assert_eq!(bus.bus_read(0x00FC), 0x00);
+        bus.bus_write8(0xFF50, 0x00);
+        assert!(!bus.boot_rom_mapped());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn post_boot_state_without_boot_rom() {
+        let path = test_rom("post_boot_state_dmg", &[0x18, 0xFE]);
+        let emulator = Emulator::new(path.to_str().unwrap());
+        let registers = &emulator.cpu().cpu_registers;
+        assert_eq!(
+            (registers.a, registers.pc, registers.sp),
+            (0x01, 0x100, 0xFFFE)
+        );
+        let bus = &emulator.cpu().bus;
+        assert_eq!(bus.bus_read(0xFF04), 0xAB);
+        assert_eq!(bus.bus_read(0xFF07), 0xF8);
+        assert_eq!(bus.bus_read(0xFF26), 0xF1);
+        assert_eq!(bus.bus_read(0xFF24), 0x77);
+        assert_eq!(bus.bus_read(0xFF40), 0x91);
+        fs::remove_file(&path).unwrap();
+
+        let path = cgb_test_rom("post_boot_state_cgb", &[0x18, 0xFE]);
+        let emulator = Emulator::new(path.to_str().unwrap());
+        let registers = &emulator.cpu().cpu_registers;
+        assert_eq!((registers.a, registers.d, registers.e), (0x11, 0xFF, 0x56));
+        assert_eq!(emulator.cpu().bus.bus_read(0xFF26), 0xF1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn model_post_boot_registers_and_cgb_mode() {
+        let path = cgb_test_rom("model_post_boot_registers", &[0x18, 0xFE]);
+        let rom_file = path.to_str().unwrap();
+
+        // (model, A, B, CGB mode)
+        for (model, a, b, cgb) in [
+            (Model::Dmg0, 0x01, 0xFF, false),
+            (Model::Dmg, 0x01, 0x00, false),
+            (Model::Mgb, 0xFF, 0x00, false),
+            (Model::Sgb, 0x01, 0x00, false),
+            (Model::Cgb, 0x11, 0x00, true),
+            (Model::Agb, 0x11, 0x01, true),
+        ] {
+            let emulator = Emulator::with_model(rom_file, model);
+            let registers = &emulator.cpu().cpu_registers;
+            assert_eq!((registers.a, registers.b), (a, b), "{:?}", model);
+            assert_eq!(emulator.cpu().bus.cgb_mode(), cgb, "{:?}", model);
+        }
+        fs::remove_file(&path).unwrap();
+
+        // a DMG game on CGB hardware still sees A = 0x11, without CGB mode
+        let path = test_rom("model_dmg_game_on_cgb", &[0x18, 0xFE]);
+        let emulator = Emulator::with_model(path.to_str().unwrap(), Model::Cgb);
+        assert_eq!(emulator.cpu().cpu_registers.a, 0x11);
+        assert!(!emulator.cpu().bus.cgb_mode());
+        assert_eq!(emulator.cpu().bus.bus_read(0xFF70), 0x00);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn model_post_boot_io() {
+        let path = cgb_test_rom("model_post_boot_io", &[0x18, 0xFE]);
+        let rom_file = path.to_str().unwrap();
+
+        let bus = Bus::with_model(Cartridge::load(rom_file), Model::Dmg);
+        assert_eq!(bus.bus_read(0xFF0F), 0xE1);
+        assert_eq!(bus.bus_read(0xFF02), 0x7E);
+        assert_eq!(bus.bus_read(0xFF04), 0xAB);
+        assert_eq!(bus.bus_read(0xFF26), 0xF1);
+        assert_eq!(bus.bus_read(0xFF40), 0x91);
+
+        // no boot sound on the SGB
+        let bus = Bus::with_model(Cartridge::load(rom_file), Model::Sgb);
+        assert_eq!(bus.bus_read(0xFF26), 0xF0);
+
+        let bus = Bus::with_model(Cartridge::load(rom_file), Model::Cgb);
+        assert_eq!(bus.bus_read(0xFF0F), 0xE1);
+        assert_eq!(bus.bus_read(0xFF02), 0x7F);
+        assert_eq!(bus.bus_read(0xFF4D), 0x7E);
+        assert_eq!(bus.bus_read(0xFF4F), 0xFE);
+        assert_eq!(bus.bus_read(0xFF55), 0xFF);
+        assert_eq!(bus.bus_read(0xFF70), 0xF8);
+        assert_eq!(bus.bus_read(0xFF69), 0xFF);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,587 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::cpu::instruction::RegisterType;
+use crate::cpu::util::ValueEnum;
+use crate::emulator::Emulator;
+use crate::watchpoint::{Access, WatchHit, Watchpoint};
+
+/*
+ GDB remote serial protocol stub, one client at a time over TCP.
+
+ Registers are 16 bits little endian, numbered like the start of the Z80
+ target so `set architecture z80` lines them up:
+   0 AF  1 BC  2 DE  3 HL  4 SP  5 PC
+
+ Breakpoints (Z0/Z1) are kept by the stub instead of being patched into
+ memory, so they work in ROM as well. Z2/Z3/Z4 become watchpoints.
+*/
+const GDB_REGISTERS: [RegisterType; 6] = [
+    RegisterType::AF,
+    RegisterType::BC,
+    RegisterType::DE,
+    RegisterType::HL,
+    RegisterType::SP,
+    RegisterType::PC,
+];
+
+// largest packet the stub handles, advertised by qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// ^C sent by the client to stop a continue
+const INTERRUPT: u8 = 0x03;
+// instructions run between two checks for an interrupt from the client
+const INTERRUPT_POLL_INSTRUCTIONS: usize = 10_000;
+
+/// What to do after a packet.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Step(Option<u16>),
+    Continue(Option<u16>),
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Waits for a client on localhost:`port` and serves it until it detaches.
+    pub fn listen(&mut self, emulator: &mut Emulator, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        println!("waiting for a GDB client on {}", listener.local_addr()?);
+        let (stream, address) = listener.accept()?;
+        println!("GDB client connected from {}", address);
+        self.serve(emulator, stream)
+    }
+
+    pub fn serve(&mut self, emulator: &mut Emulator, stream: TcpStream) -> io::Result<()> {
+        let mut connection = Connection::new(stream);
+        while let Some(packet) = connection.read_packet()? {
+            match self.handle_packet(emulator, &packet) {
+                Action::Reply(reply) => connection.send_packet(&reply)?,
+                Action::Step(address) => {
+                    let reply = self.resume(emulator, address, true, || connection.interrupted());
+                    connection.send_packet(&reply)?;
+                }
+                Action::Continue(address) => {
+                    let reply = self.resume(emulator, address, false, || connection.interrupted());
+                    connection.send_packet(&reply)?;
+                }
+                Action::Detach => {
+                    connection.send_packet("OK")?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, emulator: &mut Emulator, packet: &str) -> Action {
+        let command_length = packet.chars().next().map_or(0, char::len_utf8);
+        let (command, args) = packet.split_at(command_length);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => GDB_REGISTERS
+                .iter()
+                .map(|&register| register_hex(read_register(emulator, register)))
+                .collect(),
+            "G" => {
+                let values: Option<Vec<u16>> = (0..GDB_REGISTERS.len())
+                    .map(|index| {
+                        args.get(index * 4..index * 4 + 4)
+                            .and_then(parse_register_value)
+                    })
+                    .collect();
+                match values {
+                    Some(values) => {
+                        for (&register, value) in GDB_REGISTERS.iter().zip(values) {
+                            write_register(emulator, register, value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "p" => match parse_number(args).and_then(|index| GDB_REGISTERS.get(index)) {
+                Some(&register) => register_hex(read_register(emulator, register)),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let register = args
+                    .split_once('=')
+                    .and_then(|(index, value)| {
+                        Some((*GDB_REGISTERS.get(parse_number(index)?)?, value))
+                    })
+                    .and_then(|(register, value)| Some((register, parse_register_value(value)?)));
+                match register {
+                    Some((register, value)) => {
+                        write_register(emulator, register, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            // two hex digits per byte have to fit in a packet
+            "m" => match parse_address_length(args) {
+                Some((address, length)) if length <= PACKET_SIZE / 2 => (0..length)
+                    .map(|offset| {
+                        let address = address.wrapping_add(offset as u16);
+                        format!("{:02x}", emulator.cpu().bus.bus_read(address))
+                    })
+                    .collect(),
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_address_length(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    (bytes.len() == length).then_some((address, bytes))
+                });
+                match write {
+                    Some((address, bytes)) => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            let address = address.wrapping_add(offset as u16);
+                            emulator.cpu_mut().bus.bus_write8(address, byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.set_point(emulator, command == "Z", args),
+            "s" => return Action::Step(parse_hex_u16(args)),
+            "c" => return Action::Continue(parse_hex_u16(args)),
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            "H" => "OK".to_string(),
+            _ => match packet {
+                "qAttached" => "1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "qC" => "QC1".to_string(),
+                _ if packet.starts_with("qSupported") => format!("PacketSize={:x}", PACKET_SIZE),
+                // unsupported, the empty reply tells the client so
+                _ => String::new(),
+            },
+        };
+        Action::Reply(reply)
+    }
+
+    /// `Z<type>,<addr>,<kind>` inserts and `z...` removes a breakpoint (types
+    /// 0 and 1) or a write, read or access watchpoint (types 2, 3 and 4).
+    fn set_point(&mut self, emulator: &mut Emulator, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(point_type), Some(address), Some(length)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Some(address), Some(length)) = (parse_hex_u16(address), parse_number(length)) else {
+            return "E01".to_string();
+        };
+
+        let access = match point_type {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::ReadWrite,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint {
+            start: address,
+            end: (address as usize)
+                .saturating_add(length.max(1) - 1)
+                .min(0xFFFF) as u16,
+            access,
+            value: None,
+        };
+        let watchpoints = &mut emulator.cpu_mut().watchpoints;
+        if insert {
+            watchpoints.add(watchpoint);
+        } else if let Some(index) = watchpoints.list().iter().position(|w| *w == watchpoint) {
+            watchpoints.remove(index);
+        }
+        "OK".to_string()
+    }
+
+    /// Runs one instruction, or until a breakpoint, a watchpoint or the
+    /// client interrupts, from `address` when given. Returns the stop reply.
+    fn resume<F: FnMut() -> bool>(
+        &mut self,
+        emulator: &mut Emulator,
+        address: Option<u16>,
+        step: bool,
+        mut interrupted: F,
+    ) -> String {
+        if let Some(address) = address {
+            emulator.cpu_mut().cpu_registers.pc = address;
+        }
+        let mut instructions = 0;
+        loop {
+            emulator.step_instruction();
+            if let Some(hit) = emulator.cpu_mut().watchpoints.take_hit() {
+                return watch_reply(&hit);
+            }
+            if step || self.breakpoints.contains(&emulator.cpu().cpu_registers.pc) {
+                return format!("S{:02x}", SIGTRAP);
+            }
+            instructions += 1;
+            if instructions % INTERRUPT_POLL_INSTRUCTIONS == 0 && interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+}
+
+fn watch_reply(hit: &WatchHit) -> String {
+    let kind = match hit.access {
+        Access::Write => "watch",
+        Access::Read => "rwatch",
+        Access::ReadWrite => "awatch",
+    };
+    format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)
+}
+
+fn read_register(emulator: &Emulator, register: RegisterType) -> u16 {
+    u16::try_from(emulator.cpu().cpu_registers.get_register(register)).unwrap()
+}
+
+fn write_register(emulator: &mut Emulator, register: RegisterType, value: u16) {
+    emulator
+        .cpu_mut()
+        .cpu_registers
+        .set_register(register, ValueEnum::Data16(value));
+}
+
+/// Registers go over the wire in target byte order.
+fn register_hex(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, (value >> 8) as u8)
+}
+
+fn parse_register_value(text: &str) -> Option<u16> {
+    let bytes = parse_hex_bytes(text)?;
+    match bytes[..] {
+        [lo, hi] => Some(u16::from_le_bytes([lo, hi])),
+        _ => None,
+    }
+}
+
+/// Addresses and lengths are plain hex numbers.
+fn parse_hex_u16(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_address_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex_u16(address)?, parse_number(length)?))
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+/// Packet framing (`$data#checksum`) and acknowledgements over the socket.
+struct Connection {
+    stream: TcpStream,
+    // bytes read while polling for an interrupt
+    pending: VecDeque<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// None once the client closed the connection.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0; 1];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Next packet with a valid checksum, acknowledged. Acks from the client
+    /// and stray interrupts are skipped.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            for digit in &mut sum {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    /// Reads what the client sent without waiting, true on ^C or when the
+    /// client went away.
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buffer = [0; 64];
+        let mut closed = false;
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(count) => self.pending.extend(&buffer[..count]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        self.stream.set_nonblocking(false).ok();
+
+        match self.pending.iter().position(|&byte| byte == INTERRUPT) {
+            Some(index) => {
+                self.pending.drain(..=index);
+                true
+            }
+            None => closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{checksum, Action, GdbStub};
+    use crate::emulator::tests::test_rom;
+    use crate::emulator::Emulator;
+
+    /*
+     0150: ld a,$42
+     0152: ld ($C000),a
+     0155: jr 0155
+    */
+    const CODE: [u8; 7] = [0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE];
+
+    fn reply(stub: &mut GdbStub, emulator: &mut Emulator, packet: &str) -> String {
+        match stub.handle_packet(emulator, packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("{} gave {:?}", packet, action),
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let path = test_rom("gdb_registers_and_memory", &CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut stub = GdbStub::new();
+
+        // AF BC DE HL SP PC after boot, little endian
+        assert_eq!(
+            reply(&mut stub, &mut emulator, "g"),
+            "80011300d8004d01feff0001"
+        );
+        assert_eq!(reply(&mut stub, &mut emulator, "p5"), "0001");
+        assert_eq!(reply(&mut stub, &mut emulator, "P5=5001"), "OK");
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x150);
+        // the low nibble of F doesn't exist
+        assert_eq!(reply(&mut stub, &mut emulator, "P0=ff12"), "OK");
+        assert_eq!(reply(&mut stub, &mut emulator, "p0"), "f012");
+        assert_eq!(reply(&mut stub, &mut emulator, "p6"), "E01");
+
+        assert_eq!(reply(&mut stub, &mut emulator, "m150,3"), "3e42ea");
+        assert_eq!(reply(&mut stub, &mut emulator, "Mc000,2:beef"), "OK");
+        assert_eq!(reply(&mut stub, &mut emulator, "mc000,2"), "beef");
+        assert_eq!(reply(&mut stub, &mut emulator, "Mc000,2:be"), "E01");
+        assert_eq!(reply(&mut stub, &mut emulator, "m0,800").len(), 0x1000);
+        assert_eq!(reply(&mut stub, &mut emulator, "m0,7fffffff"), "E01");
+        assert_eq!(reply(&mut stub, &mut emulator, "vMustReplyEmpty"), "");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn breakpoints_watchpoints_and_steps() {
+        let path = test_rom("gdb_breakpoints", &CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let mut stub = GdbStub::new();
+
+        assert_eq!(stub.handle_packet(&mut emulator, "s"), Action::Step(None));
+        assert_eq!(
+            stub.handle_packet(&mut emulator, "c150"),
+            Action::Continue(Some(0x150))
+        );
+
+        assert_eq!(reply(&mut stub, &mut emulator, "Z0,152,1"), "OK");
+        assert_eq!(stub.resume(&mut emulator, None, false, || false), "S05");
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x152);
+        assert_eq!(reply(&mut stub, &mut emulator, "z0,152,1"), "OK");
+
+        assert_eq!(reply(&mut stub, &mut emulator, "Z2,c000,1"), "OK");
+        assert_eq!(
+            stub.resume(&mut emulator, None, false, || false),
+            "T05watch:c000;"
+        );
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x155);
+        assert_eq!(reply(&mut stub, &mut emulator, "z2,c000,1"), "OK");
+        assert!(emulator.cpu().watchpoints.is_empty());
+
+        // oversized ranges stop at the end of the address space
+        assert_eq!(reply(&mut stub, &mut emulator, "Z3,c000,10000"), "OK");
+        assert_eq!(emulator.cpu().watchpoints.list()[0].end, 0xFFFF);
+        assert_eq!(reply(&mut stub, &mut emulator, "z3,c000,10000"), "OK");
+        assert!(emulator.cpu().watchpoints.is_empty());
+
+        assert_eq!(stub.resume(&mut emulator, None, true, || false), "S05");
+        assert_eq!(stub.resume(&mut emulator, None, false, || true), "S02");
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        write!(stream, "${}#{:02x}", data, checksum(data)).unwrap();
+    }
+
+    /// Reads the ack and the reply packet, returns the reply data.
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        let mut byte = [0; 1];
+        while !received.ends_with(b"#") {
+            stream.read_exact(&mut byte).unwrap();
+            received.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        let text = String::from_utf8(received).unwrap();
+        let data = &text[text.find('$').unwrap() + 1..text.len() - 1];
+        assert_eq!(sum, format!("{:02x}", checksum(data)).as_bytes());
+        data.to_string()
+    }
+
+    #[test]
+    fn session_over_tcp() {
+        let path = test_rom("gdb_session_over_tcp", &CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut replies = Vec::new();
+            for packet in ["qSupported:swbreak+", "?", "Z0,150,1", "c", "s", "p5"] {
+                send(&mut stream, packet);
+                replies.push(receive(&mut stream));
+            }
+            // a bad checksum is nacked
+            stream.write_all(b"$g#00").unwrap();
+            let mut nack = [0; 1];
+            stream.read_exact(&mut nack).unwrap();
+            replies.push(String::from_utf8(nack.to_vec()).unwrap());
+
+            send(&mut stream, "c");
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(&[0x03]).unwrap();
+            replies.push(receive(&mut stream));
+            send(&mut stream, "D");
+            replies.push(receive(&mut stream));
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new().serve(&mut emulator, stream).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            [
+                "PacketSize=1000",
+                "S05",
+                "OK",
+                "S05",
+                "S05",
+                "5201",
+                "-",
+                "S02",
+                "OK"
+            ]
+        );
+        assert_eq!(emulator.cpu().cpu_registers.pc, 0x155);
+        fs::remove_file(&path).unwrap();
+    }
+}
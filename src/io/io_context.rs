@@ -1,6 +1,9 @@
-use super::{Serial, Timer};
+use super::{Button, Joypad, Serial, Timer};
+use crate::cpu::context::InterruptType;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 pub struct IO {
+    joypad: Joypad,
     serial: Serial,
     pub timer: Timer,
     interrupt_flag_register: u8,
@@ -9,21 +12,40 @@ pub struct IO {
 impl IO {
     pub fn new() -> Self {
         Self {
+            joypad: Joypad::new(),
             serial: Serial::new(),
             timer: Timer::new(),
             interrupt_flag_register: 0,
         }
     }
 
+    /// The upper 3 bits of IF don't exist and read as 1.
     pub fn get_if_flag(&self) -> u8 {
-        self.interrupt_flag_register
+        0xE0 | self.interrupt_flag_register
     }
 
     pub fn set_if_flag(&mut self, value: u8) {
-        self.interrupt_flag_register = value;
+        self.interrupt_flag_register = value & 0x1F;
+    }
+
+    fn request_interrupt(&mut self, interrupt: Option<InterruptType>) {
+        if let Some(interrupt) = interrupt {
+            self.interrupt_flag_register |= interrupt as u8;
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let interrupt = self.joypad.press(button);
+        self.request_interrupt(interrupt);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
     }
     pub fn io_read(&self, address: u16) -> u8 {
-        if address == 0xFF01 || address == 0xFF02 {
+        if address == 0xFF00 {
+            self.joypad.joypad_read()
+        } else if address == 0xFF01 || address == 0xFF02 {
             self.serial.io_read(address)
         } else if address >= 0xFF04 && address <= 0xFF07 {
             self.timer.timer_read(address)
@@ -36,7 +58,10 @@ impl IO {
         }
     }
     pub fn io_write(&mut self, address: u16, value: u8) {
-        if address == 0xFF01 || address == 0xFF02 {
+        if address == 0xFF00 {
+            let interrupt = self.joypad.joypad_write(value);
+            self.request_interrupt(interrupt);
+        } else if address == 0xFF01 || address == 0xFF02 {
             self.serial.io_write(address, value);
         } else if address >= 0xFF04 && address <= 0xFF07 {
             self.timer.timer_write(address, value);
@@ -48,3 +73,22 @@ impl IO {
         }
     }
 }
+
+impl SaveState for IO {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.serial.save_state(writer);
+        self.timer.save_state(writer);
+        writer.write_u8(self.interrupt_flag_register);
+        self.joypad.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.serial.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.interrupt_flag_register = reader.read_u8()? & 0x1F;
+        if reader.version >= 4 {
+            self.joypad.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
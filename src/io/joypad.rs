@@ -0,0 +1,155 @@
+use crate::cpu::context::InterruptType;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/*
+ FF00 P1/JOYP
+ Bit5   Select action buttons    (0=Select)
+ Bit4   Select direction buttons (0=Select)
+ Bit3   Down  or Start           (0=Pressed) (read only)
+ Bit2   Up    or Select          (0=Pressed) (read only)
+ Bit1   Left  or B               (0=Pressed) (read only)
+ Bit0   Right or A               (0=Pressed) (read only)
+*/
+
+/// Directions use the low nibble and action buttons the high nibble of the
+/// pressed mask, in the order of their P1 input line.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
+impl Button {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+
+    /// P10-P13, a selected and pressed button pulls its line low.
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        lines
+    }
+
+    /// The joypad interrupt is requested when any input line goes from high to low.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) -> Option<InterruptType> {
+        let old_lines = self.input_lines();
+        change(self);
+        if old_lines & !self.input_lines() != 0 {
+            Some(InterruptType::JOYPAD)
+        } else {
+            None
+        }
+    }
+
+    pub fn joypad_read(&self) -> u8 {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    pub fn joypad_write(&mut self, value: u8) -> Option<InterruptType> {
+        self.update(|joypad| joypad.select = value & 0x30)
+    }
+
+    pub fn press(&mut self, button: Button) -> Option<InterruptType> {
+        self.update(|joypad| joypad.pressed |= button.mask())
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.mask() != 0
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the pressed buttons belong to the host, only the line selection is saved
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.select = reader.read_u8()? & 0x30;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Button, Joypad};
+    use crate::cpu::context::InterruptType;
+
+    #[test]
+    fn reads_the_selected_group() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.joypad_read(), 0xFF);
+
+        joypad.press(Button::Down);
+        joypad.press(Button::A);
+        // nothing selected, every line stays high
+        assert_eq!(joypad.joypad_read(), 0xFF);
+
+        joypad.joypad_write(0x20);
+        assert_eq!(joypad.joypad_read(), 0xE7);
+
+        joypad.joypad_write(0x10);
+        assert_eq!(joypad.joypad_read(), 0xDE);
+
+        joypad.joypad_write(0x00);
+        assert_eq!(joypad.joypad_read(), 0xC6);
+
+        joypad.release(Button::Down);
+        assert!(!joypad.is_pressed(Button::Down));
+        assert_eq!(joypad.joypad_read(), 0xCE);
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low_transition() {
+        let mut joypad = Joypad::new();
+        joypad.joypad_write(0x10);
+
+        // directions aren't selected
+        assert_eq!(joypad.press(Button::Left), None);
+        assert_eq!(joypad.press(Button::Start), Some(InterruptType::JOYPAD));
+        // already low
+        assert_eq!(joypad.press(Button::Start), None);
+
+        // selecting the directions pulls the Left line low
+        assert_eq!(joypad.joypad_write(0x00), Some(InterruptType::JOYPAD));
+        joypad.release(Button::Start);
+        assert_eq!(joypad.joypad_write(0x20), None);
+    }
+}
@@ -1,7 +1,9 @@
 pub mod io_context;
+pub mod joypad;
 pub mod serial;
 pub mod timer;
 
 pub use io_context::IO;
+pub use joypad::{Button, Joypad};
 pub use serial::Serial;
 pub use timer::Timer;
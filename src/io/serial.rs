@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
 pub struct Serial {
     serial_transfer_data: u8,    // 0xFF01
     serial_transfer_control: u8, //0xFF02
@@ -29,3 +31,16 @@ impl Serial {
         }
     }
 }
+
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.serial_transfer_data);
+        writer.write_u8(self.serial_transfer_control);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.serial_transfer_data = reader.read_u8()?;
+        self.serial_transfer_control = reader.read_u8()?;
+        Ok(())
+    }
+}
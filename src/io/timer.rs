@@ -1,4 +1,5 @@
 use crate::cpu::context::InterruptType;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Timer {
@@ -18,6 +19,16 @@ impl Timer {
         }
     }
 
+    /// Sets the internal counter, for the state left by the boot ROM.
+    pub fn set_div(&mut self, div: u16) {
+        self.div = div;
+    }
+
+    /// Internal 16-bit counter, DIV is its upper byte.
+    pub fn div(&self) -> u16 {
+        self.div
+    }
+
     pub fn timer_tick(&mut self) -> Option<InterruptType> {
         let prev_div = self.div;
         self.div += 1;
@@ -83,6 +94,23 @@ impl Timer {
     }
 }
 
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.div);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.div = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::cpu::context::InterruptType;
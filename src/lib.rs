@@ -1,9 +1,19 @@
+pub mod apu;
+pub mod audio;
 pub mod bus;
 pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
 pub mod dma;
 pub mod emu;
+pub mod emulator;
+pub mod gdb;
 pub mod io;
+pub mod model;
 pub mod ppu;
 pub mod ram;
+pub mod savestate;
+#[cfg(feature = "window")]
 pub mod ui;
+pub mod watchpoint;
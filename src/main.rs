@@ -1,18 +1,266 @@
-use std::{env, process::exit};
+use std::{
+    env, fs,
+    io::{self, Write},
+    process::exit,
+};
 
 use rusty_gb::{
-    cpu::{instruction_set::InstructionSet, CpuContext},
+    audio::OUTPUT_SAMPLE_RATES,
+    bus::Bus,
+    cartridge::Cartridge,
+    debugger::{parse_address, Debugger},
+    disassembler::disassemble_bank,
     emu::EmuContext,
+    emulator::Emulator,
+    gdb::GdbStub,
+    model::{Model, MODEL_NAMES},
+    ppu::{PpuRenderer, RENDERER_NAMES},
 };
 
+// 10 seconds
+const DEFAULT_RECORD_FRAMES: usize = 600;
+
+struct Options {
+    rom_file: String,
+    boot_rom: Option<String>,
+    model: Option<Model>,
+    renderer: PpuRenderer,
+    debug: bool,
+    trace: Option<String>,
+    gdb_port: Option<u16>,
+    record_audio: Option<String>,
+    sample_rate: u32,
+    frames: usize,
+}
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} <rom_file> [options]", program);
+    println!(
+        "       {} disasm <rom_file> [--bank <n>] [--from <addr>]",
+        program
+    );
+    println!("  --boot-rom <file>          run the DMG or CGB boot ROM first");
+    println!(
+        "  --model <name>             hardware to emulate: {} (default: cgb for",
+        MODEL_NAMES.join(", ")
+    );
+    println!("                             CGB games, dmg for the others)");
+    println!(
+        "  --renderer <name>          mode 3 renderer: {} (default: scanline)",
+        RENDERER_NAMES.join(", ")
+    );
+    println!("  --debug                    run in the debugger REPL, without a window");
+    println!("  --gdb <port>               run without a window, debugged by a GDB client");
+    println!("                             connecting to localhost:<port>");
+    println!("  --trace <file>             run without a window and log every instruction");
+    println!("                             in the gameboy-doctor format");
+    println!("  --record-audio <file.wav>  run without a window and record the sound");
+    println!("  --sample-rate <44100|48000> sample rate of the recording (default 48000)");
+    println!(
+        "  --frames <n>               frames to record or trace (default {})",
+        DEFAULT_RECORD_FRAMES
+    );
+    exit(1);
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let program = &args[0];
+    let mut options = Options {
+        rom_file: String::new(),
+        boot_rom: None,
+        model: None,
+        renderer: PpuRenderer::default(),
+        debug: false,
+        trace: None,
+        gdb_port: None,
+        record_audio: None,
+        sample_rate: 48000,
+        frames: DEFAULT_RECORD_FRAMES,
+    };
+
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--boot-rom" => {
+                options.boot_rom = Some(args.next().unwrap_or_else(|| usage(program)).clone())
+            }
+            "--model" => {
+                options.model = Some(
+                    args.next()
+                        .and_then(|name| Model::try_from(name.as_str()).ok())
+                        .unwrap_or_else(|| usage(program)),
+                )
+            }
+            "--renderer" => {
+                options.renderer = args
+                    .next()
+                    .and_then(|name| PpuRenderer::try_from(name.as_str()).ok())
+                    .unwrap_or_else(|| usage(program))
+            }
+            "--debug" => options.debug = true,
+            "--gdb" => {
+                options.gdb_port = Some(
+                    args.next()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or_else(|| usage(program)),
+                )
+            }
+            "--trace" => {
+                options.trace = Some(args.next().unwrap_or_else(|| usage(program)).clone())
+            }
+            "--record-audio" => {
+                options.record_audio = Some(args.next().unwrap_or_else(|| usage(program)).clone())
+            }
+            "--sample-rate" => {
+                options.sample_rate = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|rate| OUTPUT_SAMPLE_RATES.contains(rate))
+                    .unwrap_or_else(|| usage(program))
+            }
+            "--frames" => {
+                options.frames = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage(program))
+            }
+            _ if arg.starts_with("--") || !options.rom_file.is_empty() => usage(program),
+            _ => options.rom_file = arg.clone(),
+        }
+    }
+
+    if options.rom_file.is_empty() {
+        usage(program);
+    }
+    options
+}
+
+/// `disasm <rom_file> [--bank <n>] [--from <addr>]`, prints the bank with the
+/// code reachable from `--from` (hex) decoded and the rest as data.
+fn disasm(args: &[String]) {
+    let program = &args[0];
+    let mut rom_file = None;
+    let mut bank = 0;
+    let mut from = None;
+
+    let mut args = args[2..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bank" => {
+                bank = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage(program))
+            }
+            "--from" => {
+                from = Some(
+                    args.next()
+                        .and_then(|value| parse_address(value).ok())
+                        .unwrap_or_else(|| usage(program)),
+                )
+            }
+            _ if arg.starts_with("--") || rom_file.is_some() => usage(program),
+            _ => rom_file = Some(arg),
+        }
+    }
+
+    let rom_file = rom_file.unwrap_or_else(|| usage(program));
+    let rom = fs::read(rom_file).unwrap_or_else(|e| {
+        println!("cannot read {}: {}", rom_file, e);
+        exit(1);
+    });
+    match disassemble_bank(&rom, bank, from) {
+        Ok(lines) => {
+            // stops quietly when piped into head
+            let mut stdout = io::stdout().lock();
+            for line in lines {
+                if writeln!(stdout, "{}", line).is_err() {
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        print!("Usage: {} <rom_file>\n", args[0]);
-        exit(1);
+    if args.get(1).map(String::as_str) == Some("disasm") {
+        disasm(&args);
+        return;
+    }
+    let options = parse_args(&args);
+
+    let bus = match options.model {
+        Some(model) => Bus::with_model(Cartridge::load(&options.rom_file), model),
+        None => Bus::new(&options.rom_file),
+    };
+    let mut emulator = match &options.boot_rom {
+        Some(path) => {
+            let boot_rom = fs::read(path).unwrap_or_else(|e| {
+                println!("cannot read boot ROM {}: {}", path, e);
+                exit(1);
+            });
+            Emulator::with_boot_rom(bus, boot_rom).unwrap_or_else(|e| {
+                println!("{}", e);
+                exit(1);
+            })
+        }
+        None => Emulator::with_bus(bus),
+    };
+    emulator.set_renderer(options.renderer);
+
+    if options.debug {
+        let stdin = io::stdin();
+        if let Err(e) = Debugger::new().run(&mut emulator, stdin.lock(), io::stdout()) {
+            println!("debugger stopped: {}", e);
+        }
+        io::stdout().flush().ok();
+        return;
+    }
+
+    if let Some(port) = options.gdb_port {
+        if let Err(e) = GdbStub::new().listen(&mut emulator, port) {
+            println!("GDB stub stopped: {}", e);
+            exit(1);
+        }
+        return;
     }
 
-    let instruction_set = InstructionSet::new();
-    let mut cpu_context = CpuContext::new(&args[1][..], &instruction_set);
-    EmuContext.run(&mut cpu_context);
+    if let Some(path) = &options.trace {
+        if let Err(e) = EmuContext.trace(&mut emulator, path, options.frames) {
+            println!("cannot write the trace to {}: {}", path, e);
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = &options.record_audio {
+        if let Err(e) =
+            EmuContext.record_audio(&mut emulator, path, options.sample_rate, options.frames)
+        {
+            println!("cannot record audio to {}: {}", path, e);
+            exit(1);
+        }
+        return;
+    }
+
+    run_window(&mut emulator);
+}
+
+#[cfg(feature = "window")]
+fn run_window(emulator: &mut Emulator) {
+    EmuContext.run(emulator);
+}
+
+#[cfg(not(feature = "window"))]
+fn run_window(_emulator: &mut Emulator) {
+    println!(
+        "built without the `window` feature, only the headless modes are available: \
+         --debug, --gdb, --trace, --record-audio and disasm"
+    );
+    exit(1);
 }
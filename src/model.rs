@@ -0,0 +1,173 @@
+use std::convert::TryFrom;
+
+use crate::cartridge::cart::RomHeader;
+
+/*
+ Hardware the emulator pretends to be:
+   DMG0  early original Game Boy, different boot ROM and post-boot registers
+   DMG   original Game Boy
+   MGB   Game Boy Pocket (and Light), A = 0xFF after boot
+   SGB   Super Game Boy, without the SNES side
+   CGB   Game Boy Color, A = 0x11 after boot
+   AGB   Game Boy Advance in GB mode, CGB with B bit 0 set after boot
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+    Agb,
+}
+
+/*
+ IO registers as the boot ROM leaves them, written in this order. NR52 comes
+ first so the APU accepts the other sound registers. IF has the VBlank of the
+ last boot ROM frame pending.
+*/
+const POST_BOOT_IO: [(u16, u8); 26] = [
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF26, 0xF1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF40, 0x91),
+    (0xFF47, 0xFC),
+];
+
+/*
+ CGB mode registers after the CGB boot ROM: normal speed, VRAM bank 0, WRAM
+ bank 1 and no HDMA source or destination. The palettes start out white.
+*/
+const CGB_POST_BOOT_IO: [(u16, u8); 7] = [
+    (0xFF4D, 0x00),
+    (0xFF4F, 0x00),
+    (0xFF51, 0xFF),
+    (0xFF52, 0xFF),
+    (0xFF53, 0xFF),
+    (0xFF54, 0xFF),
+    (0xFF70, 0x00),
+];
+
+pub const MODEL_NAMES: [&str; 6] = ["dmg0", "dmg", "mgb", "sgb", "cgb", "agb"];
+
+impl Model {
+    /// CGB for the ROMs that use its features, DMG for the others.
+    pub fn for_header(header: &RomHeader) -> Self {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// CGB hardware, CGB mode still needs a CGB flagged ROM.
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() {
+            0x900
+        } else {
+            0x100
+        }
+    }
+
+    /// Internal DIV counter when the boot ROM hands over, it has been counting
+    /// since power on.
+    pub fn post_boot_div(&self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            // the SGB value isn't documented, its boot ROM takes longer
+            Model::Dmg | Model::Mgb | Model::Sgb => 0xABCC,
+            // depends on the header on CGB, this is a typical value
+            Model::Cgb | Model::Agb => 0x1EA0,
+        }
+    }
+
+    /// IO register writes recreating what the boot ROM hands over, the CGB
+    /// registers are only there in CGB mode.
+    pub fn post_boot_io(&self, cgb_mode: bool) -> Vec<(u16, u8)> {
+        let mut registers = POST_BOOT_IO.to_vec();
+        // bit 1 of SC selects the fast serial clock on CGB
+        registers.push((0xFF02, if self.is_cgb() { 0x7F } else { 0x7E }));
+        // NR14 retriggers channel 1 at an inaudible frequency like the end of
+        // the boot sound, the SGB boot ROM is silent and leaves it off
+        registers.push((0xFF14, if *self == Model::Sgb { 0x3F } else { 0xBF }));
+        if cgb_mode {
+            registers.extend(CGB_POST_BOOT_IO);
+        }
+        registers
+    }
+
+    /// Writing STAT briefly enables every STAT source on DMG, the spurious
+    /// interrupt it causes in HBlank and VBlank is fixed on CGB.
+    pub fn has_stat_write_bug(&self) -> bool {
+        !self.is_cgb()
+    }
+}
+
+impl TryFrom<&str> for Model {
+    type Error = String;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!(
+                "unknown model {}, expected one of {}",
+                name,
+                MODEL_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, MODEL_NAMES};
+
+    #[test]
+    fn model_names() {
+        for name in MODEL_NAMES {
+            let model = Model::try_from(name).unwrap();
+            assert_eq!(format!("{:?}", model).to_ascii_lowercase(), name);
+        }
+        assert_eq!(Model::try_from("CGB"), Ok(Model::Cgb));
+        assert!(Model::try_from("gba").is_err());
+    }
+
+    #[test]
+    fn cgb_hardware() {
+        assert!(Model::Cgb.is_cgb() && Model::Agb.is_cgb());
+        assert!(!Model::Sgb.is_cgb());
+        assert_eq!(Model::Agb.boot_rom_size(), 0x900);
+        assert_eq!(Model::Mgb.boot_rom_size(), 0x100);
+        assert!(Model::Dmg0.has_stat_write_bug() && !Model::Cgb.has_stat_write_bug());
+    }
+}
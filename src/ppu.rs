@@ -1,3 +1,8 @@
+use std::collections::VecDeque;
+
+use crate::cpu::context::InterruptType;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
 #[derive(Clone, Copy)]
 pub struct OamEntry {
     y: u8,
@@ -54,16 +59,221 @@ impl OamEntry {
  Bit2-0 Palette number  **CGB Mode Only**     (OBP0-7)
 */
 
+pub const LCD_WIDTH: usize = 160;
+pub const LCD_HEIGHT: usize = 144;
+
+pub const DMG_COLORS: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const TRANSFER_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const SPRITES_PER_LINE: usize = 10;
+
+/*
+ FF40 LCDC
+ Bit7   LCD and PPU enable             (0=Off, 1=On)
+ Bit6   Window tile map area           (0=9800-9BFF, 1=9C00-9FFF)
+ Bit5   Window enable                  (0=Off, 1=On)
+ Bit4   BG and Window tile data area   (0=8800-97FF, 1=8000-8FFF)
+ Bit3   BG tile map area               (0=9800-9BFF, 1=9C00-9FFF)
+ Bit2   OBJ size                       (0=8x8, 1=8x16)
+ Bit1   OBJ enable                     (0=Off, 1=On)
+ Bit0   BG and Window enable/priority  (0=Off, 1=On)
+
+ FF41 STAT
+ Bit6   LYC=LY STAT interrupt source
+ Bit5   Mode 2 OAM STAT interrupt source
+ Bit4   Mode 1 VBlank STAT interrupt source
+ Bit3   Mode 0 HBlank STAT interrupt source
+ Bit2   LYC=LY flag                    (read only)
+ Bit1-0 PPU mode                       (read only)
+*/
+
+/*
+ CGB registers
+ FF4F VBK        Bit0 VRAM bank mapped at 8000-9FFF
+ FF68 BCPS/BGPI  Bit7 auto increment after writes, Bit5-0 palette RAM address
+ FF69 BCPD/BGPD  BG palette RAM at BCPS
+ FF6A OCPS/OBPI  same as BCPS for the OBJ palettes
+ FF6B OCPD/OBPD  OBJ palette RAM at OCPS
+
+ 8 palettes of 4 colors, little-endian RGB555: Bit4-0 red, Bit9-5 green,
+ Bit14-10 blue.
+
+ BG map attributes, in VRAM bank 1 at the same offset as the tile index:
+ Bit7   BG over OBJ     (0=Use OAM priority bit, 1=BG colors 1-3 over OBJ)
+ Bit6   Y flip
+ Bit5   X flip
+ Bit3   Tile VRAM bank
+ Bit2-0 BG palette number
+*/
+const VRAM_BANK_SIZE: usize = 0x2000;
+const PALETTE_RAM_SIZE: usize = 64;
+
+/// How mode 3 turns VRAM into pixels. `Scanline` draws the whole line at
+/// once and always takes 172 dots, `PixelFifo` runs the fetcher dot by dot so
+/// mode 3 gets longer with SCX fine scroll, the window and sprites, and
+/// register writes during the line land on the right pixels.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum PpuRenderer {
+    #[default]
+    Scanline,
+    PixelFifo,
+}
+
+pub const RENDERER_NAMES: [&str; 2] = ["fifo", "scanline"];
+
+impl TryFrom<&str> for PpuRenderer {
+    type Error = String;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(PpuRenderer::PixelFifo),
+            "scanline" => Ok(PpuRenderer::Scanline),
+            _ => Err(format!(
+                "unknown renderer {}, expected one of {}",
+                name,
+                RENDERER_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Transfer = 3,
+}
+
+impl From<u8> for PpuMode {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            _ => PpuMode::Transfer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color_index: u8,
+    attributes: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+    color_index: u8,
+    // OBP0/OBP1 on DMG, 0-7 on CGB
+    palette: u8,
+    bg_priority: bool,
+    oam_index: u8,
+}
+
+/*
+ Mode 3 state of the pixel FIFO renderer. The background fetcher takes 2 dots
+ for each of its steps (tile index, data low, data high) and pushes 8 pixels
+ once the BG FIFO is empty, one pixel leaves the FIFO per dot:
+   - the first fetch of the line is thrown away (6 dots)
+   - SCX % 8 pixels are discarded before the first one is shown
+   - starting the window clears the FIFO and restarts the fetcher (6 dots)
+   - each sprite costs 6 dots plus the wait for the current BG fetch
+*/
+#[derive(Default)]
+struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    sprites: Vec<(u8, OamEntry)>,
+    next_sprite: usize,
+    lcd_x: u8,
+    discard: u8,
+    startup: u8,
+    stall: u8,
+    fetch_dot: u8,
+    tile_x: u8,
+    tile_index: u8,
+    tile_attributes: u8,
+    tile_low: u8,
+    tile_high: u8,
+    window_active: bool,
+}
+
 pub struct PPU {
     oam_ram: [OamEntry; 40],
-    vram: [u8; 0x2000],
+    vram: [u8; 2 * VRAM_BANK_SIZE],
+
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    cgb: bool,
+    stat_write_bug: bool,
+    vram_bank: u8,
+    bcps: u8,
+    ocps: u8,
+    bg_palettes: [u8; PALETTE_RAM_SIZE],
+    obj_palettes: [u8; PALETTE_RAM_SIZE],
+
+    mode: PpuMode,
+    dots: u16,
+    stat_line: bool,
+    // the window is only drawn once LY matched WY during the frame, and has
+    // its own line counter that only moves on lines where it was drawn
+    window_triggered: bool,
+    window_line: u8,
+
+    renderer: PpuRenderer,
+    fifo: PixelFifo,
+
+    pub framebuffer: Vec<u32>,
+    pub frame_ready: bool,
 }
 
 impl PPU {
     pub fn new() -> Self {
         Self {
             oam_ram: [OamEntry::new(); 40],
-            vram: [0; 0x2000],
+            vram: [0; 2 * VRAM_BANK_SIZE],
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            cgb: false,
+            stat_write_bug: false,
+            vram_bank: 0,
+            bcps: 0,
+            ocps: 0,
+            // the CGB boot ROM leaves every palette white
+            bg_palettes: [0xFF; PALETTE_RAM_SIZE],
+            obj_palettes: [0xFF; PALETTE_RAM_SIZE],
+            mode: PpuMode::OamScan,
+            dots: 0,
+            stat_line: false,
+            window_triggered: false,
+            window_line: 0,
+            renderer: PpuRenderer::default(),
+            fifo: PixelFifo::default(),
+            framebuffer: vec![DMG_COLORS[0]; LCD_WIDTH * LCD_HEIGHT],
+            frame_ready: false,
         }
     }
 
@@ -77,13 +287,726 @@ impl PPU {
         sprite.set_field_from_offset(offset, value);
     }
     pub fn ppu_vram_read(&self, address: u16) -> u8 {
-        self.vram[address as usize - 0x8000]
+        self.vram[self.vram_offset(address)]
     }
     pub fn ppu_vram_write(&mut self, address: u16, value: u8) {
-        self.vram[address as usize - 0x8000] = value;
+        let offset = self.vram_offset(address);
+        self.vram[offset] = value;
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank as usize
+    }
+
+    fn vram_offset(&self, address: u16) -> usize {
+        self.vram_bank as usize * VRAM_BANK_SIZE + address as usize - 0x8000
+    }
+
+    /// CGB mode: VRAM bank 1, BG map attributes, color palettes and the CGB
+    /// sprite priority.
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb
+    }
+
+    /// DMG quirk: a STAT write acts as if every source was enabled for a
+    /// cycle, which raises a STAT interrupt in HBlank, VBlank or on LY=LYC.
+    pub fn set_stat_write_bug(&mut self, enabled: bool) {
+        self.stat_write_bug = enabled;
+    }
+
+    pub fn renderer(&self) -> PpuRenderer {
+        self.renderer
+    }
+
+    pub fn set_renderer(&mut self, renderer: PpuRenderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    pub fn mode(&self) -> PpuMode {
+        self.mode
+    }
+
+    pub fn lcd_read(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = ((self.ly == self.lyc) as u8) << 2;
+                0x80 | (self.stat & 0x78) | coincidence | self.mode as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF68 => 0x40 | self.bcps,
+            0xFF69 => self.bg_palettes[self.bcps as usize & 0x3F],
+            0xFF6A => 0x40 | self.ocps,
+            0xFF6B => self.obj_palettes[self.ocps as usize & 0x3F],
+            _ => 0xFF,
+        }
+    }
+
+    /// Returns the interrupts (IF bits) requested by the register write.
+    pub fn lcd_write(&mut self, address: u16, value: u8) -> u8 {
+        match address {
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+                if was_enabled && !self.lcd_enabled() {
+                    // turning the LCD off resets LY and leaves the PPU in HBlank
+                    self.ly = 0;
+                    self.dots = 0;
+                    self.mode = PpuMode::HBlank;
+                    self.stat_line = false;
+                    self.window_triggered = false;
+                    self.window_line = 0;
+                } else if !was_enabled && self.lcd_enabled() {
+                    self.mode = PpuMode::OamScan;
+                }
+            }
+            0xFF41 => {
+                if self.stat_write_bug {
+                    // LYC, VBlank and HBlank, not OAM scan
+                    self.stat = 0x58;
+                    let interrupts = self.update_stat_line();
+                    self.stat = value & 0x78;
+                    return interrupts | self.update_stat_line();
+                }
+                self.stat = value & 0x78;
+            }
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => {}
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F => self.vram_bank = value & 0x01,
+            0xFF68 => self.bcps = value & 0xBF,
+            0xFF69 => write_palette_ram(&mut self.bg_palettes, &mut self.bcps, value),
+            0xFF6A => self.ocps = value & 0xBF,
+            0xFF6B => write_palette_ram(&mut self.obj_palettes, &mut self.ocps, value),
+            _ => {}
+        }
+        self.update_stat_line()
+    }
+
+    /// Advances the PPU by one dot (T-cycle), returns the interrupts (IF bits)
+    /// to request.
+    pub fn ppu_tick(&mut self) -> u8 {
+        if !self.lcd_enabled() {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        self.dots += 1;
+
+        match self.mode {
+            PpuMode::OamScan => {
+                if self.dots == OAM_SCAN_DOTS {
+                    self.mode = PpuMode::Transfer;
+                    if self.renderer == PpuRenderer::PixelFifo {
+                        self.start_fifo_line();
+                    }
+                }
+            }
+            PpuMode::Transfer => match self.renderer {
+                PpuRenderer::Scanline => {
+                    if self.dots == OAM_SCAN_DOTS + TRANSFER_DOTS {
+                        self.render_scanline();
+                        self.mode = PpuMode::HBlank;
+                    }
+                }
+                PpuRenderer::PixelFifo => {
+                    self.fifo_dot();
+                    if self.fifo.lcd_x as usize == LCD_WIDTH {
+                        if self.fifo.window_active {
+                            self.window_line += 1;
+                        }
+                        self.mode = PpuMode::HBlank;
+                    }
+                }
+            },
+            PpuMode::HBlank | PpuMode::VBlank => {
+                if self.dots == DOTS_PER_LINE {
+                    self.dots = 0;
+                    self.ly += 1;
+
+                    if self.ly as usize == LCD_HEIGHT {
+                        self.mode = PpuMode::VBlank;
+                        self.frame_ready = true;
+                        interrupts |= InterruptType::VBLANK as u8;
+                    } else if self.ly == LINES_PER_FRAME {
+                        self.ly = 0;
+                        self.mode = PpuMode::OamScan;
+                        self.window_triggered = false;
+                        self.window_line = 0;
+                    } else if self.mode == PpuMode::HBlank {
+                        self.mode = PpuMode::OamScan;
+                    }
+                }
+            }
+        }
+
+        interrupts | self.update_stat_line()
+    }
+
+    /// The STAT interrupt fires on the rising edge of the OR of all enabled sources.
+    fn update_stat_line(&mut self) -> u8 {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x08 != 0 && self.mode == PpuMode::HBlank)
+            || (self.stat & 0x10 != 0 && self.mode == PpuMode::VBlank)
+            || (self.stat & 0x20 != 0 && self.mode == PpuMode::OamScan);
+
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+
+        if rising_edge && self.lcd_enabled() {
+            InterruptType::LCDStat as u8
+        } else {
+            0
+        }
+    }
+
+    /// Offset in VRAM of the tile, following the LCDC bit 4 addressing mode.
+    fn tile_data_offset(&self, tile_index: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile_index as usize * 16
+        } else {
+            (0x1000 + (tile_index as i8 as isize) * 16) as usize
+        }
+    }
+
+    /// Color index (0-3) of the pixel at (x, y) of the tile.
+    fn tile_pixel(&self, tile_offset: usize, x: u8, y: u8) -> u8 {
+        let lo = self.vram[tile_offset + y as usize * 2];
+        let hi = self.vram[tile_offset + y as usize * 2 + 1];
+        let bit = 7 - x;
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    /// Pixel at (x, y) of the 256x256 tile map, with its CGB attributes.
+    fn map_pixel(&self, map_offset: usize, x: u8, y: u8) -> BgPixel {
+        let map_index = map_offset + (y as usize / 8) * 32 + x as usize / 8;
+        let tile_index = self.vram[map_index];
+        let attributes = self.map_attributes(map_index);
+
+        let tile_offset = self.tile_data_offset(tile_index) + tile_bank_offset(attributes);
+        let tile_x = if attributes & 0x20 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let tile_y = if attributes & 0x40 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        BgPixel {
+            color_index: self.tile_pixel(tile_offset, tile_x, tile_y),
+            attributes,
+        }
+    }
+
+    /// Attributes of a tile map entry, always 0 outside of CGB mode.
+    fn map_attributes(&self, map_index: usize) -> u8 {
+        if self.cgb {
+            self.vram[VRAM_BANK_SIZE + map_index]
+        } else {
+            0
+        }
+    }
+
+    fn render_background(&self, line: &mut [BgPixel; LCD_WIDTH]) {
+        let map_offset = if self.lcdc & 0x08 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        };
+        let y = self.ly.wrapping_add(self.scy);
+
+        for (x, pixel) in line.iter_mut().enumerate() {
+            *pixel = self.map_pixel(map_offset, (x as u8).wrapping_add(self.scx), y);
+        }
+    }
+
+    fn render_window(&mut self, line: &mut [BgPixel; LCD_WIDTH]) {
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+        if self.lcdc & 0x20 == 0 || !self.window_triggered || self.wx > 166 {
+            return;
+        }
+
+        let map_offset = if self.lcdc & 0x40 != 0 {
+            0x1C00
+        } else {
+            0x1800
+        };
+        let window_x = self.wx as isize - 7;
+
+        for x in window_x.max(0)..LCD_WIDTH as isize {
+            let pixel = self.map_pixel(map_offset, (x - window_x) as u8, self.window_line);
+            line[x as usize] = pixel;
+        }
+        self.window_line += 1;
+    }
+
+    fn render_scanline(&mut self) {
+        let mut bg_line = [BgPixel::default(); LCD_WIDTH];
+
+        // on DMG, LCDC bit 0 blanks both the background and the window, on
+        // CGB it only takes their priority over sprites away
+        if self.cgb || self.lcdc & 0x01 != 0 {
+            self.render_background(&mut bg_line);
+            self.render_window(&mut bg_line);
+        }
+
+        let obj_line = if self.lcdc & 0x02 != 0 {
+            self.render_sprites()
+        } else {
+            [ObjPixel::default(); LCD_WIDTH]
+        };
+
+        let line_start = self.ly as usize * LCD_WIDTH;
+        for x in 0..LCD_WIDTH {
+            self.framebuffer[line_start + x] = self.pixel_color(bg_line[x], obj_line[x]);
+        }
+    }
+
+    /// Final color of a pixel, the sprite priority follows the DMG or the CGB
+    /// rules.
+    fn pixel_color(&self, bg: BgPixel, obj: ObjPixel) -> u32 {
+        let obj_visible = obj.color_index != 0
+            && self.lcdc & 0x02 != 0
+            && if self.cgb {
+                // LCDC bit 0 off puts every sprite over the background
+                self.lcdc & 0x01 == 0
+                    || bg.color_index == 0
+                    || (!obj.bg_priority && bg.attributes & 0x80 == 0)
+            } else {
+                !(obj.bg_priority && bg.color_index != 0)
+            };
+
+        match (obj_visible, self.cgb) {
+            (true, true) => cgb_color(&self.obj_palettes, obj.palette, obj.color_index),
+            (false, true) => cgb_color(&self.bg_palettes, bg.attributes & 0x07, bg.color_index),
+            (true, false) => {
+                let palette = if obj.palette == 1 {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                DMG_COLORS[palette_shade(palette, obj.color_index) as usize]
+            }
+            (false, false) => DMG_COLORS[palette_shade(self.bgp, bg.color_index) as usize],
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// The first 10 sprites in OAM order that overlap the current line, with
+    /// their OAM index.
+    fn scan_oam(&self) -> Vec<(u8, OamEntry)> {
+        let height = self.sprite_height() as i16;
+        let ly = self.ly as i16;
+        self.oam_ram
+            .iter()
+            .enumerate()
+            .filter(|(_, sprite)| {
+                let top = sprite.y as i16 - 16;
+                ly >= top && ly < top + height
+            })
+            .take(SPRITES_PER_LINE)
+            .map(|(index, sprite)| (index as u8, *sprite))
+            .collect()
+    }
+
+    fn sprite_palette(&self, sprite: &OamEntry) -> u8 {
+        if self.cgb {
+            sprite.flags & 0x07
+        } else {
+            (sprite.flags >> 4) & 0x01
+        }
+    }
+
+    /// Color index of the sprite's `column` on the current line, flips applied.
+    fn sprite_pixel(&self, sprite: &OamEntry, column: u8) -> u8 {
+        let height = self.sprite_height();
+        let mut row = (self.ly as i16 - (sprite.y as i16 - 16)) as u8;
+        if sprite.flags & 0x40 != 0 {
+            row = height - 1 - row;
+        }
+        // 8x16 sprites ignore bit 0 of the tile index
+        let tile_index = if height == 16 {
+            sprite.tile_index & 0xFE
+        } else {
+            sprite.tile_index
+        };
+        let tile_x = if sprite.flags & 0x20 != 0 {
+            7 - column
+        } else {
+            column
+        };
+        let bank_offset = if self.cgb {
+            tile_bank_offset(sprite.flags)
+        } else {
+            0
+        };
+        self.tile_pixel(tile_index as usize * 16 + bank_offset, tile_x, row)
+    }
+
+    /// Sprite pixels of the current line, the pixel of the sprite with the
+    /// highest priority wins even if the background hides it later.
+    fn render_sprites(&self) -> [ObjPixel; LCD_WIDTH] {
+        let mut sprites = self.scan_oam();
+        // on DMG the sprite with the smaller X wins, OAM order breaks ties,
+        // on CGB only the OAM order counts
+        if !self.cgb {
+            sprites.sort_by_key(|(_, sprite)| sprite.x);
+        }
+
+        let mut line = [ObjPixel::default(); LCD_WIDTH];
+        for (oam_index, sprite) in sprites {
+            for column in 0..8u8 {
+                let x = sprite.x as i16 - 8 + column as i16;
+                if x < 0 || x >= LCD_WIDTH as i16 || line[x as usize].color_index != 0 {
+                    continue;
+                }
+                let color_index = self.sprite_pixel(&sprite, column);
+                if color_index != 0 {
+                    line[x as usize] = ObjPixel {
+                        color_index,
+                        palette: self.sprite_palette(&sprite),
+                        bg_priority: sprite.flags & 0x80 != 0,
+                        oam_index,
+                    };
+                }
+            }
+        }
+        line
+    }
+
+    fn start_fifo_line(&mut self) {
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+
+        let mut sprites = self.scan_oam();
+        sprites.retain(|(_, sprite)| sprite.x < 168);
+        sprites.sort_by_key(|(_, sprite)| sprite.x);
+
+        self.fifo = PixelFifo {
+            sprites,
+            discard: self.scx % 8,
+            startup: 6,
+            ..PixelFifo::default()
+        };
+    }
+
+    /// One dot of mode 3 with the pixel FIFO renderer.
+    fn fifo_dot(&mut self) {
+        if self.fifo.startup > 0 {
+            self.fifo.startup -= 1;
+            return;
+        }
+
+        if self.window_starts() {
+            self.fifo.window_active = true;
+            self.fifo.bg.clear();
+            self.fifo.fetch_dot = 0;
+            self.fifo.tile_x = 0;
+            if self.wx < 7 {
+                self.fifo.discard = 7 - self.wx;
+            }
+        }
+
+        self.fetcher_step();
+
+        if self.fifo.stall == 0 && !self.fifo.bg.is_empty() && self.fifo.discard == 0 {
+            self.fetch_sprites();
+        }
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+
+        let Some(mut bg) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        let obj = self.fifo.obj.pop_front().unwrap_or_default();
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        // on DMG, LCDC bit 0 blanks both the background and the window
+        if !self.cgb && self.lcdc & 0x01 == 0 {
+            bg = BgPixel::default();
+        }
+
+        let x = self.fifo.lcd_x as usize;
+        self.framebuffer[self.ly as usize * LCD_WIDTH + x] = self.pixel_color(bg, obj);
+        self.fifo.lcd_x += 1;
+    }
+
+    fn window_starts(&self) -> bool {
+        !self.fifo.window_active
+            && self.lcdc & 0x20 != 0
+            && self.window_triggered
+            && self.wx <= 166
+            && self.fifo.lcd_x + 7 >= self.wx
+    }
+
+    /// Background fetcher, registers are sampled at the step that uses them.
+    fn fetcher_step(&mut self) {
+        self.fifo.fetch_dot = self.fifo.fetch_dot.saturating_add(1);
+
+        match self.fifo.fetch_dot {
+            2 => {
+                let (map_offset, x, y) = if self.fifo.window_active {
+                    let map_offset = if self.lcdc & 0x40 != 0 {
+                        0x1C00
+                    } else {
+                        0x1800
+                    };
+                    (map_offset, self.fifo.tile_x, self.window_line)
+                } else {
+                    let map_offset = if self.lcdc & 0x08 != 0 {
+                        0x1C00
+                    } else {
+                        0x1800
+                    };
+                    let x = ((self.scx / 8).wrapping_add(self.fifo.tile_x)) & 0x1F;
+                    (map_offset, x, self.ly.wrapping_add(self.scy))
+                };
+                let map_index = map_offset + (y as usize / 8) * 32 + x as usize;
+                self.fifo.tile_index = self.vram[map_index];
+                self.fifo.tile_attributes = self.map_attributes(map_index);
+            }
+            4 | 6 => {
+                let y = if self.fifo.window_active {
+                    self.window_line
+                } else {
+                    self.ly.wrapping_add(self.scy)
+                };
+                let attributes = self.fifo.tile_attributes;
+                let row = if attributes & 0x40 != 0 {
+                    7 - y % 8
+                } else {
+                    y % 8
+                };
+                let offset = self.tile_data_offset(self.fifo.tile_index)
+                    + tile_bank_offset(attributes)
+                    + row as usize * 2;
+                if self.fifo.fetch_dot == 4 {
+                    self.fifo.tile_low = self.vram[offset];
+                } else {
+                    self.fifo.tile_high = self.vram[offset + 1];
+                }
+            }
+            _ => {}
+        }
+
+        if self.fifo.fetch_dot >= 7 && self.fifo.bg.is_empty() {
+            let attributes = self.fifo.tile_attributes;
+            for pixel in 0..8 {
+                let bit = if attributes & 0x20 != 0 {
+                    pixel
+                } else {
+                    7 - pixel
+                };
+                let low = (self.fifo.tile_low >> bit) & 1;
+                let high = (self.fifo.tile_high >> bit) & 1;
+                self.fifo.bg.push_back(BgPixel {
+                    color_index: (high << 1) | low,
+                    attributes,
+                });
+            }
+            self.fifo.fetch_dot = 0;
+            self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+        }
+    }
+
+    /// Merges the sprites starting at the current pixel into the OBJ FIFO and
+    /// stalls the pixel output for their fetch.
+    fn fetch_sprites(&mut self) {
+        if self.lcdc & 0x02 == 0 {
+            return;
+        }
+
+        let lcd_x = self.fifo.lcd_x as i16;
+        // the background fetch in progress finishes before the first sprite
+        let mut bg_wait = 5 - self.fifo.fetch_dot.min(5);
+        while let Some((oam_index, sprite)) = self.fifo.sprites.get(self.fifo.next_sprite).copied()
+        {
+            let sprite_x = sprite.x as i16 - 8;
+            if sprite_x.max(0) > lcd_x {
+                break;
+            }
+            self.fifo.next_sprite += 1;
+
+            self.fifo.stall += 6 + bg_wait;
+            bg_wait = 0;
+
+            while self.fifo.obj.len() < 8 {
+                self.fifo.obj.push_back(ObjPixel::default());
+            }
+            for column in 0..8u8 {
+                let x = sprite_x + column as i16;
+                if x < lcd_x {
+                    continue;
+                }
+                let slot = (x - lcd_x) as usize;
+                // pixels of earlier sprites keep their place, on CGB the
+                // smaller OAM index wins instead
+                let current = self.fifo.obj[slot];
+                if current.color_index != 0 && (!self.cgb || current.oam_index < oam_index) {
+                    continue;
+                }
+                let color_index = self.sprite_pixel(&sprite, column);
+                if color_index != 0 {
+                    self.fifo.obj[slot] = ObjPixel {
+                        color_index,
+                        palette: self.sprite_palette(&sprite),
+                        bg_priority: sprite.flags & 0x80 != 0,
+                        oam_index,
+                    };
+                }
+            }
+        }
     }
 }
 
+impl SaveState for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.vram);
+        let oam: Vec<u8> = (0..0xA0)
+            .map(|offset| self.oam_ram[offset >> 2].get_field_from_offset(offset as u8))
+            .collect();
+        writer.write_bytes(&oam);
+        for register in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            writer.write_u8(register);
+        }
+        writer.write_u8(self.mode as u8);
+        writer.write_u16(self.dots);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.window_triggered);
+        writer.write_u8(self.window_line);
+        writer.write_u8(self.vram_bank);
+        writer.write_u8(self.bcps);
+        writer.write_u8(self.ocps);
+        writer.write_bytes(&self.bg_palettes);
+        writer.write_bytes(&self.obj_palettes);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        if reader.version >= 6 {
+            reader.read_into(&mut self.vram)?;
+        } else {
+            reader.read_into(&mut self.vram[..VRAM_BANK_SIZE])?;
+        }
+        let mut oam = [0; 0xA0];
+        reader.read_into(&mut oam)?;
+        for (offset, value) in oam.iter().enumerate() {
+            self.oam_ram[offset >> 2].set_field_from_offset(offset as u8, *value);
+        }
+        if reader.version >= 2 {
+            for register in [
+                &mut self.lcdc,
+                &mut self.stat,
+                &mut self.scy,
+                &mut self.scx,
+                &mut self.ly,
+                &mut self.lyc,
+                &mut self.bgp,
+                &mut self.obp0,
+                &mut self.obp1,
+                &mut self.wy,
+                &mut self.wx,
+            ] {
+                *register = reader.read_u8()?;
+            }
+            self.mode = PpuMode::from(reader.read_u8()?);
+            self.dots = reader.read_u16()?;
+            self.stat_line = reader.read_bool()?;
+        }
+        if reader.version >= 3 {
+            self.window_triggered = reader.read_bool()?;
+            self.window_line = reader.read_u8()?;
+        }
+        if reader.version >= 6 {
+            self.vram_bank = reader.read_u8()?;
+            self.bcps = reader.read_u8()?;
+            self.ocps = reader.read_u8()?;
+            reader.read_into(&mut self.bg_palettes)?;
+            reader.read_into(&mut self.obj_palettes)?;
+        }
+        // the FIFO isn't part of the snapshot, a line in progress restarts
+        if self.mode == PpuMode::Transfer && self.renderer == PpuRenderer::PixelFifo {
+            self.start_fifo_line();
+        }
+        Ok(())
+    }
+}
+
+/// Offset of VRAM bank 1 when bit 3 of BG attributes or OAM flags is set.
+fn tile_bank_offset(attributes: u8) -> usize {
+    if attributes & 0x08 != 0 {
+        VRAM_BANK_SIZE
+    } else {
+        0
+    }
+}
+
+/// BCPD/OCPD write, moves to the next byte when auto increment is on.
+fn write_palette_ram(palette_ram: &mut [u8; PALETTE_RAM_SIZE], specification: &mut u8, value: u8) {
+    palette_ram[*specification as usize & 0x3F] = value;
+    if *specification & 0x80 != 0 {
+        *specification = 0x80 | ((*specification + 1) & 0x3F);
+    }
+}
+
+/// 0RGB color of a CGB palette entry, 5 bits per channel scaled to 8.
+pub fn cgb_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color_index: u8) -> u32 {
+    let offset = palette as usize * 8 + color_index as usize * 2;
+    let color = u16::from_le_bytes([palette_ram[offset], palette_ram[offset + 1]]) as u32;
+    let scale = |channel: u32| (channel << 3) | (channel >> 2);
+    let red = scale(color & 0x1F);
+    let green = scale((color >> 5) & 0x1F);
+    let blue = scale((color >> 10) & 0x1F);
+    (red << 16) | (green << 8) | blue
+}
+
+/// Maps a color index through a BGP/OBP0/OBP1 style palette register.
+pub fn palette_shade(palette: u8, color_index: u8) -> u8 {
+    (palette >> (color_index * 2)) & 0b11
+}
+
 pub fn translate_oam_address(address: u16, dma: bool) -> (usize, u8) {
     let translated_address = if !dma {
         address - 0xFE00
@@ -101,7 +1024,488 @@ pub fn translate_oam_address(address: u16, dma: bool) -> (usize, u8) {
 
 #[cfg(test)]
 mod tests {
-    use super::translate_oam_address;
+    use super::{
+        cgb_color, translate_oam_address, PpuMode, PpuRenderer, DMG_COLORS, LCD_WIDTH, PPU,
+        RENDERER_NAMES,
+    };
+    use crate::cpu::context::InterruptType;
+
+    #[test]
+    fn renderer_names() {
+        for name in RENDERER_NAMES {
+            assert!(PpuRenderer::try_from(name).is_ok());
+        }
+        assert_eq!(PpuRenderer::try_from("FIFO"), Ok(PpuRenderer::PixelFifo));
+        assert!(PpuRenderer::try_from("dots").is_err());
+    }
+
+    fn run_dots(ppu: &mut PPU, dots: usize) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..dots {
+            interrupts |= ppu.ppu_tick();
+        }
+        interrupts
+    }
+
+    #[test]
+    fn mode_timing_within_a_line() {
+        let mut ppu = PPU::new();
+        assert_eq!(ppu.mode(), PpuMode::OamScan);
+        run_dots(&mut ppu, 80);
+        assert_eq!(ppu.mode(), PpuMode::Transfer);
+        run_dots(&mut ppu, 172);
+        assert_eq!(ppu.mode(), PpuMode::HBlank);
+        run_dots(&mut ppu, 204);
+        assert_eq!(ppu.mode(), PpuMode::OamScan);
+        assert_eq!(ppu.lcd_read(0xFF44), 1);
+    }
+
+    #[test]
+    fn vblank_interrupt_and_frame_length() {
+        let mut ppu = PPU::new();
+        let interrupts = run_dots(&mut ppu, 456 * 144 - 1);
+        assert_eq!(interrupts & InterruptType::VBLANK as u8, 0);
+
+        let interrupts = run_dots(&mut ppu, 1);
+        assert_eq!(interrupts, InterruptType::VBLANK as u8);
+        assert_eq!(ppu.mode(), PpuMode::VBlank);
+        assert_eq!(ppu.lcd_read(0xFF41) & 0b11, 1);
+        assert!(ppu.frame_ready);
+
+        run_dots(&mut ppu, 456 * 10);
+        assert_eq!(ppu.lcd_read(0xFF44), 0);
+        assert_eq!(ppu.mode(), PpuMode::OamScan);
+    }
+
+    #[test]
+    fn lyc_compare_raises_stat_interrupt() {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF45, 3);
+        ppu.lcd_write(0xFF41, 0x40);
+
+        let interrupts = run_dots(&mut ppu, 456 * 3 - 1);
+        assert_eq!(interrupts, 0);
+        assert_eq!(ppu.lcd_read(0xFF41) & 0x04, 0);
+
+        let interrupts = run_dots(&mut ppu, 1);
+        assert_eq!(interrupts, InterruptType::LCDStat as u8);
+        assert_eq!(ppu.lcd_read(0xFF41) & 0x04, 0x04);
+
+        // the line stays high for the whole line, no second interrupt
+        assert_eq!(run_dots(&mut ppu, 455), 0);
+    }
+
+    #[test]
+    fn lcd_off_resets_ly() {
+        let mut ppu = PPU::new();
+        run_dots(&mut ppu, 456 * 5);
+        ppu.lcd_write(0xFF40, 0x11);
+        assert_eq!(ppu.lcd_read(0xFF44), 0);
+        assert_eq!(run_dots(&mut ppu, 456 * 200), 0);
+        assert_eq!(ppu.lcd_read(0xFF44), 0);
+    }
+
+    // draws a vertical line of color 3 in the first column of `tile`
+    fn column_tile(ppu: &mut PPU, tile: u16) {
+        for row in 0..8 {
+            ppu.ppu_vram_write(0x8000 + tile * 16 + row * 2, 0x80);
+            ppu.ppu_vram_write(0x8000 + tile * 16 + row * 2 + 1, 0x80);
+        }
+    }
+
+    #[test]
+    fn background_scrolling_and_palette() {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF47, 0b1110_0100);
+        column_tile(&mut ppu, 1);
+        // tile (2, 1) of the map
+        ppu.ppu_vram_write(0x9800 + 32 + 2, 1);
+
+        ppu.lcd_write(0xFF42, 8);
+        ppu.lcd_write(0xFF43, 3);
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[13], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[16], DMG_COLORS[0]);
+
+        // BGP maps color 3 to shade 1
+        ppu.lcd_write(0xFF47, 0b0100_0000);
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[LCD_WIDTH + 13], DMG_COLORS[1]);
+    }
+
+    #[test]
+    fn signed_tile_data_addressing() {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF40, 0x81);
+        ppu.lcd_write(0xFF47, 0b1110_0100);
+        // tile 0xFF lives at 0x8FF0 in 0x8800 addressing
+        for row in 0..8 {
+            ppu.ppu_vram_write(0x8FF0 + row * 2, 0xFF);
+        }
+        ppu.ppu_vram_write(0x9800, 0xFF);
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[0], DMG_COLORS[1]);
+    }
+
+    #[test]
+    fn window_layer() {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF47, 0b1110_0100);
+        column_tile(&mut ppu, 1);
+        // window uses the 9C00 map, background stays on tile 0
+        ppu.ppu_vram_write(0x9C00, 1);
+        ppu.lcd_write(0xFF40, 0x91 | 0x20 | 0x40);
+        ppu.lcd_write(0xFF4A, 2);
+        ppu.lcd_write(0xFF4B, 7 + 20);
+
+        run_dots(&mut ppu, 456 * 2);
+        assert_eq!(ppu.framebuffer[LCD_WIDTH + 20], DMG_COLORS[0]);
+
+        run_dots(&mut ppu, 456);
+        let line = 2 * LCD_WIDTH;
+        assert_eq!(ppu.framebuffer[line + 19], DMG_COLORS[0]);
+        assert_eq!(ppu.framebuffer[line + 20], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[line + 21], DMG_COLORS[0]);
+    }
+
+    #[test]
+    fn stat_write_bug_on_dmg() {
+        for stat_write_bug in [false, true] {
+            let mut ppu = PPU::new();
+            ppu.set_stat_write_bug(stat_write_bug);
+            ppu.lcd_write(0xFF45, 0x10);
+            // OAM scan doesn't trigger it
+            assert_eq!(ppu.lcd_write(0xFF41, 0x00), 0);
+
+            run_dots(&mut ppu, 80 + 172);
+            assert_eq!(ppu.mode(), PpuMode::HBlank);
+            let expected = if stat_write_bug {
+                InterruptType::LCDStat as u8
+            } else {
+                0
+            };
+            assert_eq!(ppu.lcd_write(0xFF41, 0x00), expected);
+        }
+    }
+
+    fn set_sprite(ppu: &mut PPU, index: u16, y: u8, x: u8, tile_index: u8, flags: u8) {
+        let address = 0xFE00 + index * 4;
+        ppu.oam_write(address, y, false);
+        ppu.oam_write(address + 1, x, false);
+        ppu.oam_write(address + 2, tile_index, false);
+        ppu.oam_write(address + 3, flags, false);
+    }
+
+    fn sprite_ppu() -> PPU {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF40, 0x93);
+        ppu.lcd_write(0xFF47, 0b1110_0100);
+        ppu.lcd_write(0xFF48, 0b1110_0100);
+        ppu.lcd_write(0xFF49, 0b0001_1011);
+        ppu
+    }
+
+    #[test]
+    fn sprites_flip_and_palettes() {
+        let mut ppu = sprite_ppu();
+        column_tile(&mut ppu, 1);
+        set_sprite(&mut ppu, 0, 16, 8, 1, 0x00);
+        set_sprite(&mut ppu, 1, 16, 24, 1, 0x20);
+        set_sprite(&mut ppu, 2, 16, 40, 1, 0x10);
+
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[0], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[1], DMG_COLORS[0]);
+        // X flip moves the column to the right edge
+        assert_eq!(ppu.framebuffer[16], DMG_COLORS[0]);
+        assert_eq!(ppu.framebuffer[23], DMG_COLORS[3]);
+        // OBP1 maps color 3 to shade 0
+        assert_eq!(ppu.framebuffer[32], DMG_COLORS[0]);
+    }
+
+    #[test]
+    fn tall_sprites_and_y_flip() {
+        let mut ppu = sprite_ppu();
+        ppu.lcd_write(0xFF40, 0x97);
+        // only the first row of the bottom half (tile 3) is set
+        ppu.ppu_vram_write(0x8030, 0xFF);
+        ppu.ppu_vram_write(0x8031, 0xFF);
+        // bit 0 of the tile index is ignored
+        set_sprite(&mut ppu, 0, 16, 8, 3, 0x00);
+        set_sprite(&mut ppu, 1, 16, 16, 3, 0x40);
+
+        run_dots(&mut ppu, 456 * 9);
+        assert_eq!(ppu.framebuffer[8 * LCD_WIDTH], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[7 * LCD_WIDTH + 8], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[8 * LCD_WIDTH + 8], DMG_COLORS[0]);
+    }
+
+    #[test]
+    fn sprite_priority_and_line_limit() {
+        let mut ppu = sprite_ppu();
+        column_tile(&mut ppu, 1);
+        // tile 2 is a solid block of color 1
+        for row in 0..8 {
+            ppu.ppu_vram_write(0x8020 + row * 2, 0xFF);
+        }
+
+        // the smaller X wins even when it comes later in OAM
+        set_sprite(&mut ppu, 0, 16, 9, 2, 0x00);
+        set_sprite(&mut ppu, 1, 16, 8, 1, 0x00);
+        // same X, OAM order decides
+        set_sprite(&mut ppu, 2, 16, 40, 1, 0x00);
+        set_sprite(&mut ppu, 3, 16, 40, 2, 0x00);
+        // only the first 10 sprites of the line are drawn
+        for index in 4..11 {
+            set_sprite(&mut ppu, index, 16, 80, 2, 0x00);
+        }
+        set_sprite(&mut ppu, 11, 16, 120, 2, 0x00);
+
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[0], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[1], DMG_COLORS[1]);
+        assert_eq!(ppu.framebuffer[32], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[33], DMG_COLORS[1]);
+        assert_eq!(ppu.framebuffer[72], DMG_COLORS[1]);
+        assert_eq!(ppu.framebuffer[112], DMG_COLORS[0]);
+    }
+
+    #[test]
+    fn background_over_sprite_priority() {
+        let mut ppu = sprite_ppu();
+        // background tile 0 has color 1 in its left half only
+        for row in 0..8 {
+            ppu.ppu_vram_write(0x8000 + row * 2, 0xF0);
+        }
+        for row in 0..8 {
+            ppu.ppu_vram_write(0x8020 + row * 2 + 1, 0xFF);
+        }
+        set_sprite(&mut ppu, 0, 16, 8, 2, 0x80);
+
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[0], DMG_COLORS[1]);
+        assert_eq!(ppu.framebuffer[4], DMG_COLORS[2]);
+
+        // OBJ enable off hides every sprite
+        ppu.lcd_write(0xFF40, 0x91);
+        run_dots(&mut ppu, 456);
+        assert_eq!(ppu.framebuffer[LCD_WIDTH + 4], DMG_COLORS[0]);
+    }
+
+    // dots spent in mode 3 of the next line
+    fn transfer_length(ppu: &mut PPU) -> usize {
+        while ppu.mode() != PpuMode::OamScan {
+            ppu.ppu_tick();
+        }
+        while ppu.mode() != PpuMode::Transfer {
+            ppu.ppu_tick();
+        }
+        let mut dots = 0;
+        while ppu.mode() == PpuMode::Transfer {
+            ppu.ppu_tick();
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn fifo_mode_3_length() {
+        let mut ppu = sprite_ppu();
+        ppu.set_renderer(PpuRenderer::PixelFifo);
+        assert_eq!(transfer_length(&mut ppu), 172);
+
+        ppu.lcd_write(0xFF43, 3);
+        assert_eq!(transfer_length(&mut ppu), 175);
+        ppu.lcd_write(0xFF43, 0);
+
+        // a sprite at the start of a tile waits for the whole BG fetch
+        set_sprite(&mut ppu, 0, 16, 8, 0, 0x00);
+        assert_eq!(transfer_length(&mut ppu), 183);
+        // the second sprite on the same pixel only pays its own fetch
+        set_sprite(&mut ppu, 1, 16, 8, 0, 0x00);
+        assert_eq!(transfer_length(&mut ppu), 189);
+        // sprites are only fetched while OBJ is enabled
+        ppu.lcd_write(0xFF40, 0x91);
+        assert_eq!(transfer_length(&mut ppu), 172);
+
+        ppu.lcd_write(0xFF40, 0x91 | 0x20);
+        ppu.lcd_write(0xFF4B, 7 + 80);
+        assert_eq!(transfer_length(&mut ppu), 178);
+    }
+
+    #[test]
+    fn fifo_samples_scx_per_tile() {
+        let mut ppu = sprite_ppu();
+        ppu.set_renderer(PpuRenderer::PixelFifo);
+        column_tile(&mut ppu, 1);
+        ppu.ppu_vram_write(0x9800, 1);
+        ppu.ppu_vram_write(0x9805, 1);
+
+        while ppu.mode() != PpuMode::Transfer {
+            ppu.ppu_tick();
+        }
+        run_dots(&mut ppu, 30);
+        // coarse scroll changes only affect the tiles fetched afterwards
+        ppu.lcd_write(0xFF43, 8);
+        run_dots(&mut ppu, 456);
+
+        assert_eq!(ppu.framebuffer[0], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[32], DMG_COLORS[3]);
+        assert_eq!(ppu.framebuffer[40], DMG_COLORS[0]);
+    }
+
+    #[test]
+    fn fifo_matches_the_scanline_renderer() {
+        let mut scanline = sprite_ppu();
+        let mut fifo = sprite_ppu();
+        fifo.set_renderer(PpuRenderer::PixelFifo);
+
+        for ppu in [&mut scanline, &mut fifo] {
+            ppu.lcd_write(0xFF40, 0x93 | 0x20 | 0x40);
+            column_tile(ppu, 1);
+            for row in 0..8 {
+                ppu.ppu_vram_write(0x8020 + row * 2, 0b1010_0101);
+                ppu.ppu_vram_write(0x8020 + row * 2 + 1, 0b0110_0011);
+            }
+            for tile in 0..0x400 {
+                ppu.ppu_vram_write(0x9800 + tile, (tile % 3) as u8);
+                ppu.ppu_vram_write(0x9C00 + tile, 2 - (tile % 3) as u8);
+            }
+            ppu.lcd_write(0xFF42, 5);
+            ppu.lcd_write(0xFF43, 13);
+            ppu.lcd_write(0xFF4A, 40);
+            ppu.lcd_write(0xFF4B, 7 + 90);
+            set_sprite(ppu, 0, 20, 4, 2, 0x00);
+            set_sprite(ppu, 1, 30, 50, 1, 0x20);
+            set_sprite(ppu, 2, 30, 53, 2, 0x90);
+            set_sprite(ppu, 3, 60, 100, 2, 0x40);
+            set_sprite(ppu, 4, 60, 165, 1, 0x00);
+
+            run_dots(ppu, 456 * 154);
+        }
+
+        assert!(scanline.framebuffer == fifo.framebuffer);
+    }
+
+    #[test]
+    fn renders_background_tiles() {
+        let mut ppu = PPU::new();
+        ppu.lcd_write(0xFF47, 0b1110_0100);
+        // tile 1: first row is color 3, color 1, then color 0
+        ppu.ppu_vram_write(0x8010, 0b1100_0000);
+        ppu.ppu_vram_write(0x8011, 0b1000_0000);
+        ppu.ppu_vram_write(0x9801, 1);
+
+        run_dots(&mut ppu, 456);
+        let line = &ppu.framebuffer[..LCD_WIDTH];
+        assert_eq!(line[0], DMG_COLORS[0]);
+        assert_eq!(line[8], DMG_COLORS[3]);
+        assert_eq!(line[9], DMG_COLORS[1]);
+        assert_eq!(line[10], DMG_COLORS[0]);
+    }
+
+    fn set_cgb_color(ppu: &mut PPU, obj: bool, palette: u8, color_index: u8, color: u16) {
+        let (specification, data) = if obj {
+            (0xFF6A, 0xFF6B)
+        } else {
+            (0xFF68, 0xFF69)
+        };
+        ppu.lcd_write(specification, 0x80 | (palette * 8 + color_index * 2));
+        ppu.lcd_write(data, color as u8);
+        ppu.lcd_write(data, (color >> 8) as u8);
+    }
+
+    fn cgb_ppu(renderer: PpuRenderer) -> PPU {
+        let mut ppu = PPU::new();
+        ppu.set_cgb_mode(true);
+        ppu.set_renderer(renderer);
+        ppu.lcd_write(0xFF40, 0x93);
+        ppu
+    }
+
+    #[test]
+    fn cgb_palette_ram() {
+        let mut ppu = cgb_ppu(PpuRenderer::Scanline);
+        ppu.lcd_write(0xFF68, 0x80 | 0x3E);
+        ppu.lcd_write(0xFF69, 0x12);
+        ppu.lcd_write(0xFF69, 0x34);
+        // auto increment wraps around the 64 bytes
+        assert_eq!(ppu.lcd_read(0xFF68), 0x80 | 0x40);
+        ppu.lcd_write(0xFF68, 0x3E);
+        assert_eq!(ppu.lcd_read(0xFF69), 0x12);
+        ppu.lcd_write(0xFF69, 0x56);
+        // no auto increment
+        assert_eq!(ppu.lcd_read(0xFF68), 0x40 | 0x3E);
+        assert_eq!(ppu.lcd_read(0xFF69), 0x56);
+
+        set_cgb_color(&mut ppu, true, 7, 3, 0x7FFF);
+        assert_eq!(cgb_color(&ppu.obj_palettes, 7, 3), 0xFFFFFF);
+        set_cgb_color(&mut ppu, true, 0, 1, 0x001F);
+        assert_eq!(cgb_color(&ppu.obj_palettes, 0, 1), 0xFF0000);
+        set_cgb_color(&mut ppu, true, 0, 2, 0x03E0);
+        assert_eq!(cgb_color(&ppu.obj_palettes, 0, 2), 0x00FF00);
+        set_cgb_color(&mut ppu, true, 0, 3, 0x4000 | (0x10 << 10));
+        assert_eq!(cgb_color(&ppu.obj_palettes, 0, 3), 0x000084);
+    }
+
+    #[test]
+    fn cgb_background_attributes() {
+        for renderer in [PpuRenderer::Scanline, PpuRenderer::PixelFifo] {
+            let mut ppu = cgb_ppu(renderer);
+            set_cgb_color(&mut ppu, false, 2, 3, 0x001F);
+            // tile 0 in bank 1 has its left column set, bank 0 is empty
+            ppu.lcd_write(0xFF4F, 1);
+            assert_eq!(ppu.lcd_read(0xFF4F), 0xFF);
+            column_tile(&mut ppu, 0);
+            // first map entry: bank 1, X flip, palette 2
+            ppu.ppu_vram_write(0x9800, 0x08 | 0x20 | 0x02);
+            ppu.lcd_write(0xFF4F, 0);
+
+            run_dots(&mut ppu, 456);
+            assert_eq!(ppu.framebuffer[0], 0xFFFFFF);
+            assert_eq!(ppu.framebuffer[7], 0xFF0000);
+            assert_eq!(ppu.framebuffer[8], 0xFFFFFF);
+        }
+    }
+
+    #[test]
+    fn cgb_sprite_priority() {
+        for renderer in [PpuRenderer::Scanline, PpuRenderer::PixelFifo] {
+            let mut ppu = cgb_ppu(renderer);
+            set_cgb_color(&mut ppu, true, 1, 3, 0x001F);
+            set_cgb_color(&mut ppu, true, 2, 3, 0x03E0);
+            set_cgb_color(&mut ppu, false, 0, 3, 0x0000);
+            column_tile(&mut ppu, 1);
+            column_tile(&mut ppu, 2);
+            for offset in 0..16 {
+                ppu.ppu_vram_write(0x8030 + offset, 0xFF);
+            }
+
+            // OAM order wins over X
+            set_sprite(&mut ppu, 0, 16, 9, 1, 0x01);
+            set_sprite(&mut ppu, 1, 16, 8, 3, 0x02);
+            // the BG attribute puts colors 1-3 of the BG over the sprite
+            ppu.ppu_vram_write(0x9802, 2);
+            ppu.ppu_vram_write(0x9803, 2);
+            ppu.lcd_write(0xFF4F, 1);
+            ppu.ppu_vram_write(0x9802, 0x80);
+            ppu.lcd_write(0xFF4F, 0);
+            set_sprite(&mut ppu, 2, 16, 24, 1, 0x01);
+
+            run_dots(&mut ppu, 456);
+            assert_eq!(ppu.framebuffer[0], 0x00FF00);
+            assert_eq!(ppu.framebuffer[1], 0xFF0000);
+            assert_eq!(ppu.framebuffer[16], 0x000000);
+
+            // LCDC bit 0 off puts the sprites on top, the BG is still drawn
+            ppu.lcd_write(0xFF40, 0x92);
+            run_dots(&mut ppu, 456);
+            let line = LCD_WIDTH;
+            assert_eq!(ppu.framebuffer[line + 16], 0xFF0000);
+            assert_eq!(ppu.framebuffer[line + 24], 0x000000);
+        }
+    }
+
     #[test]
     fn translate_oam_address_ok() {
         assert_eq!(translate_oam_address(0xFE00, false), (0, 0));
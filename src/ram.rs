@@ -1,22 +1,54 @@
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// 8 banks of 4 KiB, on DMG only banks 0 and 1 are used
+const WRAM_BANK_SIZE: usize = 0x1000;
+
 pub struct RamContext {
-    wram: [u8; 0x2000],
+    wram: [u8; 8 * WRAM_BANK_SIZE],
     hram: [u8; 0x80],
+    // SVBK, bank mapped at D000-DFFF (0 selects bank 1)
+    wram_bank: u8,
 }
 
 impl RamContext {
     pub fn new() -> Self {
         Self {
-            wram: [0; 0x2000],
+            wram: [0; 8 * WRAM_BANK_SIZE],
             hram: [0; 0x80],
+            wram_bank: 0,
+        }
+    }
+
+    fn wram_offset(&self, address: u16) -> usize {
+        let offset = translate_wram_address(address).unwrap() as usize;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank() * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
         }
     }
 
+    /// Bank mapped at D000-DFFF.
+    pub fn wram_bank(&self) -> usize {
+        (self.wram_bank as usize & 0x07).max(1)
+    }
+
     pub fn wram_read(&self, address: u16) -> u8 {
-        self.wram[translate_wram_address(address).unwrap() as usize]
+        self.wram[self.wram_offset(address)]
     }
 
     pub fn wram_write(&mut self, address: u16, value: u8) {
-        self.wram[translate_wram_address(address).unwrap() as usize] = value;
+        let offset = self.wram_offset(address);
+        self.wram[offset] = value;
+    }
+
+    /// FF70 SVBK, CGB only.
+    pub fn svbk_read(&self) -> u8 {
+        0xF8 | self.wram_bank
+    }
+
+    pub fn svbk_write(&mut self, value: u8) {
+        self.wram_bank = value & 0x07;
     }
 
     pub fn hram_read(&self, address: u16) -> u8 {
@@ -28,6 +60,27 @@ impl RamContext {
     }
 }
 
+impl SaveState for RamContext {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.wram);
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.wram_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        if reader.version >= 6 {
+            reader.read_into(&mut self.wram)?;
+        } else {
+            reader.read_into(&mut self.wram[..2 * WRAM_BANK_SIZE])?;
+        }
+        reader.read_into(&mut self.hram)?;
+        if reader.version >= 6 {
+            self.wram_bank = reader.read_u8()?;
+        }
+        Ok(())
+    }
+}
+
 pub fn translate_wram_address(address: u16) -> Result<u16, ()> {
     let translated_address = address - 0xC000;
 
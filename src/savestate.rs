@@ -0,0 +1,460 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+};
+
+/*
+ Save state layout, all values little-endian:
+   "RGBS"              magic
+   u16                 format version
+   u8, u16             header checksum and global checksum of the ROM
+   ...                 CPU registers and flags
+   ...                 mapper, RAM, IO, PPU, DMA, IE, APU, CGB speed, HDMA, boot ROM
+
+ Byte arrays are prefixed with their length as a u32. Every format change
+ bumps SAVE_STATE_VERSION, readers check `StateReader::version` for fields
+ that only exist in newer versions so older snapshots keep loading.
+*/
+
+pub const SAVE_STATE_MAGIC: &[u8; 4] = b"RGBS";
+
+/*
+ Versions:
+   1  initial format
+   2  PPU registers and mode timing, the fake LY counter of the CPU is gone
+   3  PPU window line counter
+   4  joypad line selection
+   5  APU
+   6  CGB: WRAM and VRAM banks, color palettes, KEY1 and HDMA
+   7  H-blank DMA block waiting for the CPU
+   8  boot ROM mapping
+   9  ROM checksums moved in front of the CPU so they're checked first
+*/
+pub const SAVE_STATE_VERSION: u16 = 9;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    BootRomMissing,
+    UnexpectedEof,
+    SizeMismatch { expected: usize, found: usize },
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            SaveStateError::Io(e) => write!(f, "save state I/O error: {}", e),
+            SaveStateError::InvalidMagic => write!(f, "not a rusty_gb save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is newer than the supported version {}",
+                version, SAVE_STATE_VERSION
+            ),
+            SaveStateError::RomMismatch => write!(f, "save state was made with another ROM"),
+            SaveStateError::BootRomMissing => write!(
+                f,
+                "save state was made during the boot ROM, which isn't loaded"
+            ),
+            SaveStateError::UnexpectedEof => write!(f, "save state is truncated"),
+            SaveStateError::SizeMismatch { expected, found } => write!(
+                f,
+                "save state block has {} bytes, expected {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+/// Components that are part of a machine snapshot.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = Self { data: Vec::new() };
+        writer.data.extend_from_slice(SAVE_STATE_MAGIC);
+        writer.write_u16(SAVE_STATE_VERSION);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    pub version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        if data.len() < SAVE_STATE_MAGIC.len() || &data[..4] != SAVE_STATE_MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+        let mut reader = Self {
+            data,
+            position: SAVE_STATE_MAGIC.len(),
+            version: 0,
+        };
+        reader.version = reader.read_u16()?;
+        if reader.version > SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(reader.version));
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.position + length > self.data.len() {
+            return Err(SaveStateError::UnexpectedEof);
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Reads a byte array that must exactly fill `destination`.
+    pub fn read_into(&mut self, destination: &mut [u8]) -> Result<(), SaveStateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != destination.len() {
+            return Err(SaveStateError::SizeMismatch {
+                expected: destination.len(),
+                found: bytes.len(),
+            });
+        }
+        destination.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{SaveStateError, StateReader, StateWriter, SAVE_STATE_VERSION};
+    use crate::bus::Bus;
+    use crate::emulator::{tests::test_rom, Emulator};
+
+    /*
+     Sets up the timer and a square wave, then keeps writing tile data:
+       ld a,5; ldh (TAC),a; ld a,F3; ldh (NR12),a; ld a,87; ldh (NR14),a
+       ld hl,8000
+     loop:
+       inc a; ld (hl+),a; res 4,h; jr loop
+    */
+    const BUSY_CODE: [u8; 21] = [
+        0x3E, 0x05, 0xE0, 0x07, 0x3E, 0xF3, 0xE0, 0x12, 0x3E, 0x87, 0xE0, 0x14, 0x21, 0x00, 0x80,
+        0x3C, 0x22, 0xCB, 0xA4, 0x18, 0xFA,
+    ];
+
+    /// CPU registers, PPU, APU and timer registers and the last frame.
+    fn machine_state(emulator: &Emulator) -> (String, usize, Vec<u8>, Vec<u8>, u16, Vec<u32>) {
+        let cpu = emulator.cpu();
+        let bus = &cpu.bus;
+        let ppu = (0xFF40..=0xFF4B).map(|address| bus.ppu.lcd_read(address));
+        let timer = (0xFF05..=0xFF07).map(|address| bus.io.timer.timer_read(address));
+        let apu = (0xFF10..=0xFF3F).map(|address| bus.apu.apu_read(address));
+        (
+            format!("{:?}", cpu.cpu_registers),
+            emulator.cycles(),
+            ppu.chain(timer).collect(),
+            apu.collect(),
+            bus.io.timer.div(),
+            emulator.framebuffer().to_vec(),
+        )
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.version, SAVE_STATE_VERSION);
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX - 1);
+        let mut bytes = [0; 3];
+        reader.read_into(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert!(matches!(
+            reader.read_u8(),
+            Err(SaveStateError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn rejects_foreign_and_newer_states() {
+        assert!(matches!(
+            StateReader::new(b"nope"),
+            Err(SaveStateError::InvalidMagic)
+        ));
+
+        let mut data = StateWriter::new().into_bytes();
+        data[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            StateReader::new(&data),
+            Err(SaveStateError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn read_into_checks_the_size() {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(&[0; 4]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        let mut destination = [0; 8];
+        assert!(matches!(
+            reader.read_into(&mut destination),
+            Err(SaveStateError::SizeMismatch {
+                expected: 8,
+                found: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn machine_round_trip() {
+        let path = test_rom("savestate_round_trip", &BUSY_CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        emulator.run_frame();
+        emulator.run_frame();
+
+        let snapshot = emulator.save_snapshot();
+        emulator.run_frame();
+        let expected = machine_state(&emulator);
+        for _ in 0..3 {
+            emulator.run_frame();
+        }
+        assert_ne!(machine_state(&emulator), expected);
+
+        emulator.load_snapshot(&snapshot).unwrap();
+        assert_eq!(emulator.save_snapshot(), snapshot);
+        emulator.run_frame();
+        assert_eq!(machine_state(&emulator), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_loads_leave_the_machine_alone() {
+        let path = test_rom("savestate_other_rom", &BUSY_CODE);
+        let mut rom = fs::read(&path).unwrap();
+        rom[0x14F] = 0x42;
+        fs::write(&path, rom).unwrap();
+        let mut other = Emulator::new(path.to_str().unwrap());
+        other.run_frame();
+        let foreign = other.save_snapshot();
+        fs::remove_file(&path).unwrap();
+
+        let path = test_rom("savestate_failed_load", &BUSY_CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        emulator.run_frame();
+        emulator.run_frame();
+        let expected = emulator.save_snapshot();
+        let state = machine_state(&emulator);
+        assert!(matches!(
+            emulator.load_snapshot(&foreign),
+            Err(SaveStateError::RomMismatch)
+        ));
+        assert_eq!(machine_state(&emulator), state);
+        assert_eq!(emulator.save_snapshot(), expected);
+
+        // a truncated snapshot of the right ROM fails after the CPU was loaded
+        let truncated = Emulator::new(path.to_str().unwrap()).save_snapshot();
+        assert!(matches!(
+            emulator.load_snapshot(&truncated[..100]),
+            Err(SaveStateError::UnexpectedEof)
+        ));
+        assert_eq!(machine_state(&emulator), state);
+        assert_eq!(emulator.save_snapshot(), expected);
+
+        // a snapshot taken in the boot ROM needs one to be loaded
+        let bus = Bus::new(path.to_str().unwrap());
+        let mut booting = Emulator::with_boot_rom(bus, vec![0; 0x100]).unwrap();
+        booting.run_frame();
+        assert!(matches!(
+            emulator.load_snapshot(&booting.save_snapshot()),
+            Err(SaveStateError::BootRomMissing)
+        ));
+        assert_eq!(machine_state(&emulator), state);
+        assert_eq!(emulator.save_snapshot(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_version_1() {
+        let mut writer = StateWriter::new();
+        // CPU: A F B C D E H L, PC, SP, halted, IME, enabling IME, ticks, old PC, opcode
+        for value in [0x12, 0xB0, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE] {
+            writer.write_u8(value);
+        }
+        writer.write_u16(0x0160);
+        writer.write_u16(0xDFF0);
+        writer.write_bool(false);
+        writer.write_bool(true);
+        writer.write_bool(false);
+        writer.write_u64(123456);
+        writer.write_u16(0x015F);
+        writer.write_u8(0x3C);
+        // fake LY
+        writer.write_u8(0x44);
+        // cartridge checksums and the missing external RAM
+        writer.write_u8(0);
+        writer.write_u16(0);
+        writer.write_bytes(&[]);
+        // two WRAM banks and HRAM
+        writer.write_bytes(&[0xAA; 0x2000]);
+        writer.write_bytes(&[0xBB; 0x80]);
+        // serial, timer DIV TIMA TMA TAC, IF
+        writer.write_u8(0x01);
+        writer.write_u8(0x7E);
+        writer.write_u16(0xAB00);
+        writer.write_u8(0x10);
+        writer.write_u8(0x20);
+        writer.write_u8(0x05);
+        writer.write_u8(0x04);
+        // one VRAM bank and OAM
+        writer.write_bytes(&[0xCC; 0x2000]);
+        writer.write_bytes(&[0xDD; 0xA0]);
+        // OAM DMA, IE
+        writer.write_bool(false);
+        writer.write_u8(0);
+        writer.write_u8(0);
+        writer.write_u8(0);
+        writer.write_u8(0x05);
+        let mut data = writer.into_bytes();
+        data[4..6].copy_from_slice(&1u16.to_le_bytes());
+
+        let path = test_rom("savestate_version_1", &BUSY_CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        emulator.run_frame();
+        let lcdc = emulator.cpu().bus.ppu.lcd_read(0xFF40);
+        emulator.load_snapshot(&data).unwrap();
+
+        let cpu = emulator.cpu_mut();
+        assert_eq!(cpu.cpu_registers.a, 0x12);
+        assert_eq!(cpu.cpu_registers.l, 0xDE);
+        assert_eq!(cpu.cpu_registers.pc, 0x0160);
+        assert_eq!(cpu.cpu_registers.sp, 0xDFF0);
+        assert!(cpu.interrupt_master_enabled());
+        assert_eq!(cpu.ticks, 123456);
+        assert_eq!(cpu.bus.bus_read(0xC000), 0xAA);
+        assert_eq!(cpu.bus.bus_read(0xDFFF), 0xAA);
+        assert_eq!(cpu.bus.bus_read(0xFF80), 0xBB);
+        assert_eq!(cpu.bus.bus_read(0xFE00), 0xDD);
+        assert_eq!(cpu.bus.bus_read(0xFF05), 0x10);
+        assert_eq!(cpu.bus.bus_read(0xFFFF), 0x05);
+        // PPU registers, APU and the rest aren't in the snapshot and stay as they were
+        assert_eq!(cpu.bus.ppu.lcd_read(0xFF40), lcdc);
+        assert_eq!(cpu.bus.ppu.ppu_vram_read(0x8000), 0xCC);
+        assert_eq!(cpu.bus.ppu.vram_bank(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_version_6() {
+        let path = test_rom("savestate_version_6", &BUSY_CODE);
+        let mut emulator = Emulator::new(path.to_str().unwrap());
+        emulator.run_frame();
+        let snapshot = emulator.save_snapshot();
+
+        // version 6 has the ROM checksums after the CPU, no pending HDMA block
+        // and no boot ROM flag at the end
+        const CPU_SIZE: usize = 26;
+        let mut data = snapshot[..6].to_vec();
+        data[4..6].copy_from_slice(&6u16.to_le_bytes());
+        data.extend_from_slice(&snapshot[9..9 + CPU_SIZE]);
+        data.extend_from_slice(&snapshot[6..9]);
+        data.extend_from_slice(&snapshot[9 + CPU_SIZE..snapshot.len() - 2]);
+
+        let mut loaded = Emulator::new(path.to_str().unwrap());
+        loaded.load_snapshot(&data).unwrap();
+        assert_eq!(loaded.save_snapshot(), snapshot);
+        fs::remove_file(&path).unwrap();
+    }
+}
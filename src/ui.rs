@@ -1,12 +1,29 @@
 use core::panic;
 use std::{thread, time::Duration};
 
-use minifb::{Scale, ScaleMode, Window, WindowOptions};
+use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
 
 use crate::cpu::CpuContext;
+use crate::emulator::Emulator;
+use crate::io::Button;
 
 const TILE_COLORS: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
 
+pub const PAUSE_KEY: Key = Key::P;
+pub const FRAME_ADVANCE_KEY: Key = Key::N;
+pub const FAST_FORWARD_KEY: Key = Key::Tab;
+
+pub const KEY_MAP: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
 #[derive(Copy, Clone)]
 pub struct TilePosition {
     x: usize,
@@ -65,6 +82,24 @@ impl UI {
             .unwrap();
     }
 
+    pub fn update_frame(&mut self, framebuffer: &[u32]) {
+        self.dbg_window
+            .update_with_buffer(framebuffer, self.width, self.height)
+            .unwrap();
+    }
+
+    /// Forwards the state of the mapped keys to the joypad, the window only
+    /// polls the keyboard when it is updated.
+    pub fn update_joypad(&self, emulator: &mut Emulator) {
+        for (key, button) in KEY_MAP {
+            if self.dbg_window.is_key_down(key) {
+                emulator.press(button);
+            } else {
+                emulator.release(button);
+            }
+        }
+    }
+
     fn update_buffer_with_tile(&mut self, tile_number: usize, tile_array: [u8; 16]) {
         let mut s = String::new();
         let mut index = 0;
@@ -0,0 +1,159 @@
+use std::fmt::Display;
+use std::fmt::Result as FmtResult;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn includes(&self, access: Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> FmtResult {
+        let access_str = match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::ReadWrite => "read/write",
+        };
+        write!(f, "{}", access_str)
+    }
+}
+
+/// Address range (inclusive) checked on every CPU access, `value` limits it to
+/// reads or writes of that value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, access: Access, value: u8) -> bool {
+        (self.start..=self.end).contains(&address)
+            && self.access.includes(access)
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> FmtResult {
+        write!(f, "{:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        write!(f, " {}", self.access)?;
+        if let Some(value) = self.value {
+            write!(f, " =${:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The access that triggered a watchpoint. `pc` is the address of the
+/// instruction, the values are equal for reads.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WatchHit {
+    pub pc: u16,
+    pub address: u16,
+    pub access: Access,
+    pub old_value: u8,
+    pub new_value: u8,
+    pub bank: Option<usize>,
+}
+
+impl Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{} of {:04X} at PC {:04X}: {:02X} -> {:02X}",
+            self.access, self.address, self.pc, self.old_value, self.new_value
+        )?;
+        match self.bank {
+            Some(bank) => write!(f, " (bank {})", bank),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    hit: Option<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.list.push(watchpoint);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.list.len() {
+            Some(self.list.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn matches(&self, address: u16, access: Access, value: u8) -> bool {
+        self.list
+            .iter()
+            .any(|watchpoint| watchpoint.matches(address, access, value))
+    }
+
+    /// Keeps the first hit of an instruction, the debugger takes it after the step.
+    pub fn record_hit(&mut self, hit: WatchHit) {
+        self.hit.get_or_insert(hit);
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Watchpoint};
+
+    #[test]
+    fn range_access_and_value() {
+        let watchpoint = Watchpoint {
+            start: 0xC000,
+            end: 0xC0FF,
+            access: Access::Write,
+            value: None,
+        };
+        assert!(watchpoint.matches(0xC000, Access::Write, 0x12));
+        assert!(watchpoint.matches(0xC0FF, Access::Write, 0x12));
+        assert!(!watchpoint.matches(0xC100, Access::Write, 0x12));
+        assert!(!watchpoint.matches(0xC010, Access::Read, 0x12));
+
+        let watchpoint = Watchpoint {
+            access: Access::ReadWrite,
+            value: Some(0x42),
+            ..watchpoint
+        };
+        assert!(watchpoint.matches(0xC010, Access::Read, 0x42));
+        assert!(!watchpoint.matches(0xC010, Access::Write, 0x41));
+        assert_eq!(format!("{}", watchpoint), "C000-C0FF read/write =$42");
+    }
+}